@@ -0,0 +1,96 @@
+use crate::storage::unix_now;
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use libp2p::{gossipsub, identity, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+// How many announcements to keep for the live feed.
+const MAX_ANNOUNCEMENTS: usize = 256;
+
+/// Summary of a newly published file, enough for a distributor to decide
+/// whether to replicate it without fetching it first.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContentManifest {
+    pub cid: String,
+    pub name: String,
+    pub size: u64,
+    pub published_at: u64,
+}
+
+/// What goes over the wire: the manifest exactly as it was signed, plus the
+/// uploader's public key so anyone can check the signature.
+#[derive(Serialize, Deserialize)]
+struct SignedManifest {
+    manifest: Vec<u8>,
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Announcement {
+    pub manifest: ContentManifest,
+    pub uploader: String,
+    pub received_at: u64,
+}
+
+/// Signs `manifest` with the uploader's key, ready to publish.
+pub(crate) fn sign(keypair: &identity::Keypair, manifest: &ContentManifest) -> Result<Vec<u8>> {
+    let manifest = serde_json::to_vec(manifest)?;
+    let signed = SignedManifest {
+        signature: keypair.sign(&manifest)?,
+        public_key: keypair.public().encode_protobuf(),
+        manifest,
+    };
+    Ok(serde_json::to_vec(&signed)?)
+}
+
+/// Checks an announcement's signature and returns it with the uploader's
+/// peer ID.
+pub(crate) fn verify(data: &[u8]) -> Result<(ContentManifest, PeerId)> {
+    let signed: SignedManifest = serde_json::from_slice(data)?;
+    let public_key = identity::PublicKey::try_decode_protobuf(&signed.public_key)?;
+    if !public_key.verify(&signed.manifest, &signed.signature) {
+        return Err(anyhow!("Invalid announcement signature"));
+    }
+    let manifest: ContentManifest = serde_json::from_slice(&signed.manifest)?;
+    manifest.cid.parse::<Cid>()?;
+    Ok((manifest, public_key.to_peer_id()))
+}
+
+pub(crate) fn gossipsub_config() -> gossipsub::Config {
+    // Messages are only forwarded once we've checked the uploader's signature.
+    gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .validate_messages()
+        .build()
+        .expect("Valid gossipsub config")
+}
+
+/// Most recent announcements, newest first.
+#[derive(Default)]
+pub(crate) struct AnnouncementFeed {
+    announcements: VecDeque<Announcement>,
+}
+
+impl AnnouncementFeed {
+    pub(crate) fn push(&mut self, manifest: ContentManifest, uploader: PeerId) {
+        if self
+            .announcements
+            .iter()
+            .any(|a| a.manifest.cid == manifest.cid && a.uploader == uploader.to_string())
+        {
+            return;
+        }
+        self.announcements.push_front(Announcement {
+            manifest,
+            uploader: uploader.to_string(),
+            received_at: unix_now(),
+        });
+        self.announcements.truncate(MAX_ANNOUNCEMENTS);
+    }
+
+    pub(crate) fn list(&self) -> Vec<Announcement> {
+        self.announcements.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,874 @@
+use crate::bitswap::{DontHaves, PresenceReader};
+use crate::config::BandwidthLimits;
+use crate::network::is_kad_protocol;
+use crate::throttle::{Direction, Throttle};
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox};
+use libp2p::metrics::Registry;
+use libp2p::{relay, PeerId};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+use tracing::warn;
+
+// Lifetime totals, keyed by "total", "relayed", "transport/<stack>",
+// "peer/<peer id>" and "protocol/<label>".
+const BANDWIDTH_TREE: &[u8] = b"BOXPEER.BANDWIDTH";
+const TOTAL_KEY: &str = "total";
+const RELAYED_KEY: &str = "relayed";
+const TRANSPORT_PREFIX: &str = "transport/";
+const PEER_PREFIX: &str = "peer/";
+const PROTOCOL_PREFIX: &str = "protocol/";
+
+// How often rates are sampled and totals written to sled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+// The counter `SwarmBuilder::with_bandwidth_metrics` registers, one per
+// transport stack and direction.
+const BANDWIDTH_METRIC: &str = "libp2p_bandwidth_bytes_total{";
+// Transport stacks come from remote addresses, so past this many the rest
+// are lumped together rather than growing the map and the tree.
+const MAX_TRANSPORTS: usize = 32;
+const OTHER_TRANSPORT: &str = "other";
+// Past this many peers, the one we've exchanged the least with is forgotten
+// to make room for a new one.
+const MAX_PEERS: usize = 256;
+// Give up on working out a stream's protocol after this many bytes.
+const MAX_SNIFF_LEN: usize = 1024;
+const UNKNOWN_PROTOCOL: &str = "unknown";
+
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct TrafficStats {
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+impl TrafficStats {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let read = |range: std::ops::Range<usize>| {
+            bytes
+                .get(range)
+                .and_then(|b| b.try_into().ok())
+                .map(u64::from_be_bytes)
+                .unwrap_or_default()
+        };
+        Self {
+            inbound: read(0..8),
+            outbound: read(8..16),
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.inbound.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.outbound.to_be_bytes());
+        bytes
+    }
+
+    fn add(&mut self, other: TrafficStats) {
+        self.inbound += other.inbound;
+        self.outbound += other.outbound;
+    }
+
+    fn sum(&self) -> u64 {
+        self.inbound + self.outbound
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct TransportBandwidth {
+    // The transport protocols used, e.g. "/ip4/tcp" or "/ip4/udp/quic-v1".
+    pub transport: String,
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PeerBandwidth {
+    pub peer_id: String,
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ProtocolBandwidth {
+    // "bitswap", "kad", "identify" and so on, or the protocol name for
+    // protocols without a short one.
+    pub protocol: String,
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BandwidthStats {
+    pub total: TrafficStats,
+    // Bytes per second averaged over the last sample interval.
+    pub inbound_rate: f64,
+    pub outbound_rate: f64,
+    pub transports: Vec<TransportBandwidth>,
+    pub peers: Vec<PeerBandwidth>,
+    pub protocols: Vec<ProtocolBandwidth>,
+}
+
+#[derive(Default)]
+struct Traffic {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+}
+
+impl Traffic {
+    fn new(stats: TrafficStats) -> Self {
+        Self {
+            inbound: AtomicU64::new(stats.inbound),
+            outbound: AtomicU64::new(stats.outbound),
+        }
+    }
+
+    fn add(&self, stats: TrafficStats) {
+        self.inbound.fetch_add(stats.inbound, Ordering::Relaxed);
+        self.outbound.fetch_add(stats.outbound, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            inbound: self.inbound.load(Ordering::Relaxed),
+            outbound: self.outbound.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct MeterInner {
+    // Totals from earlier sessions; libp2p's counters start from zero.
+    saved_total: TrafficStats,
+    saved_transports: HashMap<String, TrafficStats>,
+    registry: OnceLock<Registry>,
+    // Circuits through us while acting as a relay.
+    relayed: Arc<Traffic>,
+    peers: Mutex<HashMap<PeerId, Arc<Traffic>>>,
+    protocols: Mutex<HashMap<String, Arc<Traffic>>>,
+    rates: Mutex<(f64, f64)>,
+    tree: sled::Tree,
+    throttle: Throttle,
+    dont_haves: DontHaves,
+}
+
+/// Reports the bytes libp2p's bandwidth metrics count on every connection,
+/// with totals that survive restarts. Direct connections are also metered
+/// stream by stream for the per-peer and per-protocol breakdown, and their
+/// bitswap streams are held to the configured rate limits.
+#[derive(Clone)]
+pub(crate) struct BandwidthMeter {
+    inner: Arc<MeterInner>,
+}
+
+impl BandwidthMeter {
+    pub(crate) fn open(db: &sled::Db, limits: BandwidthLimits) -> sled::Result<Self> {
+        let tree = db.open_tree(BANDWIDTH_TREE)?;
+        let mut saved_total = TrafficStats::default();
+        let mut saved_transports = HashMap::new();
+        let mut relayed = TrafficStats::default();
+        let mut peers = HashMap::new();
+        let mut protocols = HashMap::new();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            let name = String::from_utf8_lossy(&key);
+            let traffic = TrafficStats::from_bytes(&value);
+            if name == TOTAL_KEY {
+                saved_total = traffic;
+            } else if name == RELAYED_KEY {
+                relayed = traffic;
+            } else if let Some(transport) = name.strip_prefix(TRANSPORT_PREFIX) {
+                saved_transports.insert(transport.to_string(), traffic);
+            } else if let Some(peer) = name.strip_prefix(PEER_PREFIX) {
+                if let Ok(peer) = peer.parse() {
+                    peers.insert(peer, Arc::new(Traffic::new(traffic)));
+                }
+            } else if let Some(protocol) = name.strip_prefix(PROTOCOL_PREFIX) {
+                protocols.insert(protocol.to_string(), Arc::new(Traffic::new(traffic)));
+            }
+        }
+        while peers.len() > MAX_PEERS {
+            forget_quietest(&mut peers);
+        }
+
+        Ok(Self {
+            inner: Arc::new(MeterInner {
+                saved_total,
+                saved_transports,
+                registry: OnceLock::new(),
+                relayed: Arc::new(Traffic::new(relayed)),
+                peers: Mutex::new(peers),
+                protocols: Mutex::new(protocols),
+                rates: Default::default(),
+                tree,
+                throttle: Throttle::new(limits),
+                dont_haves: DontHaves::default(),
+            }),
+        })
+    }
+
+    /// Hands over the registry the swarm's transport reports into. Until
+    /// then only the saved totals are known.
+    pub(crate) fn attach(&self, registry: Registry) {
+        if self.inner.registry.set(registry).is_err() {
+            warn!("Bandwidth metrics registry attached twice");
+        }
+    }
+
+    /// Samples transfer rates and persists totals every `SAMPLE_INTERVAL`.
+    pub(crate) fn spawn_sampler(&self) {
+        let meter = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            let mut last = (Instant::now(), meter.totals().0);
+            loop {
+                interval.tick().await;
+                let now = (Instant::now(), meter.totals().0);
+                let elapsed = now.0.duration_since(last.0).as_secs_f64().max(1.0);
+                *meter.inner.rates.lock().unwrap() = (
+                    (now.1.inbound - last.1.inbound) as f64 / elapsed,
+                    (now.1.outbound - last.1.outbound) as f64 / elapsed,
+                );
+                last = now;
+                if let Err(e) = meter.persist() {
+                    warn!("Failed to persist bandwidth totals: {:?}", e);
+                }
+            }
+        });
+    }
+
+    pub(crate) fn stats(&self) -> BandwidthStats {
+        let (inbound_rate, outbound_rate) = *self.inner.rates.lock().unwrap();
+        let (total, transports) = self.totals();
+        let mut transports: Vec<TransportBandwidth> = transports
+            .into_iter()
+            .map(|(transport, stats)| TransportBandwidth {
+                transport,
+                inbound: stats.inbound,
+                outbound: stats.outbound,
+            })
+            .collect();
+        transports.sort_by_key(|t| std::cmp::Reverse(t.inbound + t.outbound));
+
+        let mut peers: Vec<PeerBandwidth> = self
+            .peers()
+            .into_iter()
+            .map(|(peer, stats)| PeerBandwidth {
+                peer_id: peer.to_string(),
+                inbound: stats.inbound,
+                outbound: stats.outbound,
+            })
+            .collect();
+        peers.sort_by_key(|p| std::cmp::Reverse(p.inbound + p.outbound));
+
+        let mut protocols: Vec<ProtocolBandwidth> = self
+            .protocols()
+            .into_iter()
+            .map(|(protocol, stats)| ProtocolBandwidth {
+                protocol,
+                inbound: stats.inbound,
+                outbound: stats.outbound,
+            })
+            .collect();
+        protocols.sort_by_key(|p| std::cmp::Reverse(p.inbound + p.outbound));
+
+        BandwidthStats {
+            total,
+            inbound_rate,
+            outbound_rate,
+            transports,
+            peers,
+            protocols,
+        }
+    }
+
+    /// Writes the current totals out; also called on shutdown so the last
+    /// few seconds of traffic aren't lost.
+    pub(crate) fn persist(&self) -> sled::Result<()> {
+        let (total, transports) = self.totals();
+        let peers = self.peers();
+        let mut batch = sled::Batch::default();
+        batch.insert(TOTAL_KEY, total.to_bytes());
+        batch.insert(RELAYED_KEY, self.relayed().to_bytes());
+        for (transport, traffic) in transports {
+            batch.insert(
+                format!("{}{}", TRANSPORT_PREFIX, transport).as_bytes(),
+                traffic.to_bytes(),
+            );
+        }
+        // Peers forgotten to stay under `MAX_PEERS` go from the tree too.
+        for key in self.inner.tree.scan_prefix(PEER_PREFIX).keys() {
+            let key = key?;
+            let forgotten = String::from_utf8_lossy(&key)
+                .strip_prefix(PEER_PREFIX)
+                .and_then(|peer| peer.parse::<PeerId>().ok())
+                .is_none_or(|peer| !peers.contains_key(&peer));
+            if forgotten {
+                batch.remove(key);
+            }
+        }
+        for (peer, traffic) in peers {
+            batch.insert(
+                format!("{}{}", PEER_PREFIX, peer).as_bytes(),
+                traffic.to_bytes(),
+            );
+        }
+        for (protocol, traffic) in self.protocols() {
+            batch.insert(
+                format!("{}{}", PROTOCOL_PREFIX, protocol).as_bytes(),
+                traffic.to_bytes(),
+            );
+        }
+        self.inner.tree.apply_batch(batch)
+    }
+
+    /// Lifetime totals, overall and per transport stack.
+    fn totals(&self) -> (TrafficStats, HashMap<String, TrafficStats>) {
+        let mut total = self.inner.saved_total;
+        let mut transports = self.inner.saved_transports.clone();
+        for (transport, traffic) in self.session() {
+            total.add(traffic);
+            let key = if transports.contains_key(&transport) || transports.len() < MAX_TRANSPORTS {
+                transport
+            } else {
+                OTHER_TRANSPORT.to_string()
+            };
+            transports.entry(key).or_default().add(traffic);
+        }
+        (total, transports)
+    }
+
+    /// What libp2p has counted since the swarm started.
+    fn session(&self) -> HashMap<String, TrafficStats> {
+        let Some(registry) = self.inner.registry.get() else {
+            return HashMap::new();
+        };
+        let mut text = String::new();
+        if let Err(e) = prometheus_client::encoding::text::encode(&mut text, registry) {
+            warn!("Failed to read bandwidth metrics: {:?}", e);
+        }
+        parse_bandwidth(&text)
+    }
+
+    /// Bytes carried over circuits we relay, from the hop streams their
+    /// source peers open to us. Inbound is what the source sent.
+    pub(crate) fn relayed(&self) -> TrafficStats {
+        self.inner.relayed.stats()
+    }
+
+    fn peers(&self) -> HashMap<PeerId, TrafficStats> {
+        let peers = self.inner.peers.lock().unwrap();
+        peers
+            .iter()
+            .map(|(peer, traffic)| (*peer, traffic.stats()))
+            .collect()
+    }
+
+    fn protocols(&self) -> HashMap<String, TrafficStats> {
+        let protocols = self.inner.protocols.lock().unwrap();
+        protocols
+            .iter()
+            .map(|(protocol, traffic)| (protocol.clone(), traffic.stats()))
+            .collect()
+    }
+
+    fn peer(&self, peer: PeerId) -> Arc<Traffic> {
+        let mut peers = self.inner.peers.lock().unwrap();
+        if !peers.contains_key(&peer) && peers.len() >= MAX_PEERS {
+            forget_quietest(&mut peers);
+        }
+        peers.entry(peer).or_default().clone()
+    }
+
+    fn protocol(&self, label: &str) -> Arc<Traffic> {
+        self.inner
+            .protocols
+            .lock()
+            .unwrap()
+            .entry(label.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Where the DONT_HAVEs peers send us over bitswap are queued.
+    pub(crate) fn dont_haves(&self) -> DontHaves {
+        self.inner.dont_haves.clone()
+    }
+
+    pub(crate) fn limits(&self) -> BandwidthLimits {
+        self.inner.throttle.limits()
+    }
+
+    pub(crate) fn set_limits(&self, limits: BandwidthLimits) {
+        self.inner.throttle.set_limits(limits);
+    }
+
+    /// Wraps a connection's muxer so every stream opened on it is metered,
+    /// and bitswap streams are held to the bandwidth limits.
+    pub(crate) fn meter_connection(&self, peer: PeerId, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        StreamMuxerBox::new(MeteredMuxer {
+            inner: muxer,
+            peer_id: peer,
+            peer: self.peer(peer),
+            meter: self.clone(),
+        })
+    }
+}
+
+/// Drops the peer with the least traffic that has no open connection, which
+/// is what keeps the entry alive otherwise.
+fn forget_quietest(peers: &mut HashMap<PeerId, Arc<Traffic>>) {
+    let quietest = peers
+        .iter()
+        .filter(|(_, traffic)| Arc::strong_count(traffic) == 1)
+        .min_by_key(|(_, traffic)| traffic.stats().sum())
+        .map(|(peer, _)| *peer);
+    if let Some(peer) = quietest {
+        peers.remove(&peer);
+    }
+}
+
+/// Picks the bandwidth counters out of the registry's text exposition,
+/// lines like `libp2p_bandwidth_bytes_total{protocols="/ip4/tcp",direction="Inbound"} 42`.
+fn parse_bandwidth(text: &str) -> HashMap<String, TrafficStats> {
+    let mut transports: HashMap<String, TrafficStats> = HashMap::new();
+    for line in text.lines() {
+        let Some((labels, value)) = line
+            .strip_prefix(BANDWIDTH_METRIC)
+            .and_then(|rest| rest.split_once("} "))
+        else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse::<u64>() else {
+            continue;
+        };
+        let (mut transport, mut inbound) = (None, None);
+        for label in labels.split(',') {
+            match label.split_once('=') {
+                Some(("protocols", v)) => transport = Some(v.trim_matches('"')),
+                Some(("direction", v)) => inbound = Some(v.trim_matches('"') == "Inbound"),
+                _ => {}
+            }
+        }
+        let (Some(transport), Some(inbound)) = (transport, inbound) else {
+            continue;
+        };
+        let traffic = transports.entry(transport.to_string()).or_default();
+        if inbound {
+            traffic.inbound += value;
+        } else {
+            traffic.outbound += value;
+        }
+    }
+    transports
+}
+
+/// Groups the protocols we run under short names, keeping anything else as
+/// is. Only protocols we offer or ask for get this far, so the set is small.
+fn protocol_label(protocol: &str) -> &str {
+    if protocol.contains("/bitswap") {
+        "bitswap"
+    } else if is_kad_protocol(protocol) {
+        "kad"
+    } else if protocol.starts_with("/ipfs/id/") {
+        "identify"
+    } else if protocol.starts_with("/ipfs/ping/") {
+        "ping"
+    } else if protocol.starts_with("/meshsub/") {
+        "gossipsub"
+    } else if protocol.starts_with("/libp2p/circuit/relay/") {
+        "relay"
+    } else if protocol.starts_with("/libp2p/autonat/") {
+        "autonat"
+    } else if protocol.starts_with("/libp2p/dcutr") {
+        "dcutr"
+    } else {
+        protocol
+    }
+}
+
+struct MeteredMuxer {
+    inner: StreamMuxerBox,
+    peer_id: PeerId,
+    peer: Arc<Traffic>,
+    meter: BandwidthMeter,
+}
+
+impl MeteredMuxer {
+    fn wrap(&self, stream: SubstreamBox, opened_locally: bool) -> MeteredStream {
+        MeteredStream {
+            inner: stream,
+            peer_id: self.peer_id,
+            peer: self.peer.clone(),
+            meter: self.meter.clone(),
+            opened_locally,
+            protocol: ProtocolState::Sniffing {
+                buf: Vec::new(),
+                pending: TrafficStats::default(),
+            },
+            relayed: None,
+            throttled: false,
+            presences: None,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+impl StreamMuxer for MeteredMuxer {
+    type Substream = MeteredStream;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner)
+            .poll_inbound(cx)
+            .map_ok(|stream| this.wrap(stream, false))
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner)
+            .poll_outbound(cx)
+            .map_ok(|stream| this.wrap(stream, true))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll(cx)
+    }
+}
+
+enum ProtocolState {
+    // Bytes seen before the protocol is known are held back and credited
+    // once multistream-select has settled on one.
+    Sniffing { buf: Vec<u8>, pending: TrafficStats },
+    Known(Arc<Traffic>),
+}
+
+struct MeteredStream {
+    inner: SubstreamBox,
+    peer_id: PeerId,
+    peer: Arc<Traffic>,
+    meter: BandwidthMeter,
+    opened_locally: bool,
+    protocol: ProtocolState,
+    // Set on hop streams other peers open to us, which carry the circuits
+    // we relay once the reservation or connect exchange is over.
+    relayed: Option<Arc<Traffic>>,
+    // Bitswap streams, which carry the block traffic the limits are for.
+    throttled: bool,
+    // Set on bitswap streams other peers open to us, which is how they
+    // answer our wantlists.
+    presences: Option<PresenceReader>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl MeteredStream {
+    fn record(&mut self, traffic: TrafficStats) {
+        self.peer.add(traffic);
+        if let Some(relayed) = &self.relayed {
+            relayed.add(traffic);
+        }
+        match &mut self.protocol {
+            ProtocolState::Known(protocol) => protocol.add(traffic),
+            ProtocolState::Sniffing { pending, .. } => pending.add(traffic),
+        }
+    }
+
+    /// Feeds bytes sent by the side listening for the protocol, i.e. the
+    /// remote for streams we opened and us for streams they opened. The
+    /// listener echoes the protocol it accepts, so that's where we look.
+    fn sniff(&mut self, data: &[u8]) {
+        let ProtocolState::Sniffing { buf, pending } = &mut self.protocol else {
+            return;
+        };
+        buf.extend_from_slice(data);
+        let label = match negotiated_protocol(buf) {
+            Sniffed::NeedMore if buf.len() <= MAX_SNIFF_LEN => return,
+            Sniffed::Protocol(protocol) => {
+                if !self.opened_locally && protocol == relay::HOP_PROTOCOL_NAME.as_ref() {
+                    self.relayed = Some(self.meter.inner.relayed.clone());
+                }
+                protocol_label(&protocol).to_string()
+            }
+            _ => UNKNOWN_PROTOCOL.to_string(),
+        };
+        let traffic = self.meter.protocol(&label);
+        traffic.add(*pending);
+        self.protocol = ProtocolState::Known(traffic);
+        self.throttled = label == "bitswap";
+        if self.throttled && !self.opened_locally {
+            self.presences = Some(PresenceReader::default());
+        }
+    }
+
+    /// Resolves to how many of `wanted` bytes the rate limits let through,
+    /// sleeping until the token buckets have refilled if need be.
+    fn poll_admit(
+        &mut self,
+        cx: &mut Context<'_>,
+        direction: Direction,
+        wanted: usize,
+    ) -> Poll<usize> {
+        if !self.throttled || wanted == 0 {
+            return Poll::Ready(wanted);
+        }
+        let delay = match direction {
+            Direction::Upload => &mut self.write_delay,
+            Direction::Download => &mut self.read_delay,
+        };
+        loop {
+            if let Some(sleep) = delay.as_mut() {
+                futures::ready!(sleep.as_mut().poll(cx));
+                *delay = None;
+            }
+            match self
+                .meter
+                .inner
+                .throttle
+                .admit(self.peer_id, direction, wanted)
+            {
+                Ok(allowed) => return Poll::Ready(allowed),
+                Err(wait) => *delay = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+
+    fn consume(&self, direction: Direction, bytes: usize) {
+        if self.throttled {
+            self.meter
+                .inner
+                .throttle
+                .consume(self.peer_id, direction, bytes);
+        }
+    }
+}
+
+impl Drop for MeteredStream {
+    fn drop(&mut self) {
+        if let ProtocolState::Sniffing { pending, .. } = &self.protocol {
+            self.meter.protocol(UNKNOWN_PROTOCOL).add(*pending);
+        }
+    }
+}
+
+impl AsyncRead for MeteredStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let len = futures::ready!(this.poll_admit(cx, Direction::Download, buf.len()));
+        let n = futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]))?;
+        this.record(TrafficStats {
+            inbound: n as u64,
+            outbound: 0,
+        });
+        this.consume(Direction::Download, n);
+        if let Some(presences) = &mut this.presences {
+            let dont_haves = &this.meter.inner.dont_haves;
+            presences.feed(&buf[..n], |cid| dont_haves.push(this.peer_id, cid));
+        }
+        if this.opened_locally {
+            this.sniff(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for MeteredStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let len = futures::ready!(this.poll_admit(cx, Direction::Upload, buf.len()));
+        let n = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.record(TrafficStats {
+            inbound: 0,
+            outbound: n as u64,
+        });
+        this.consume(Direction::Upload, n);
+        if !this.opened_locally {
+            this.sniff(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Sniffed {
+    NeedMore,
+    Protocol(String),
+    Unknown,
+}
+
+/// Walks the multistream-select messages in `buf`, each a varint length
+/// followed by a newline terminated string, skipping the header and
+/// rejections until a protocol name shows up.
+fn negotiated_protocol(buf: &[u8]) -> Sniffed {
+    let mut rest = buf;
+    loop {
+        let Some((len, used)) = read_uvarint(rest) else {
+            return Sniffed::NeedMore;
+        };
+        if len > MAX_SNIFF_LEN {
+            return Sniffed::Unknown;
+        }
+        if rest.len() < used + len {
+            return Sniffed::NeedMore;
+        }
+        let message = &rest[used..used + len];
+        rest = &rest[used + len..];
+        let message = message.strip_suffix(b"\n").unwrap_or(message);
+        if message == b"/multistream/1.0.0" || !message.starts_with(b"/") {
+            continue;
+        }
+        return match String::from_utf8(message.to_vec()) {
+            Ok(protocol) => Sniffed::Protocol(protocol),
+            Err(_) => Sniffed::Unknown,
+        };
+    }
+}
+
+pub(crate) fn read_uvarint(buf: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in buf.iter().take(9).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bandwidth_counters() {
+        let text = "\
+# HELP libp2p_bandwidth_bytes Bandwidth usage by direction and transport protocols.
+# TYPE libp2p_bandwidth_bytes counter
+# UNIT libp2p_bandwidth_bytes bytes
+libp2p_bandwidth_bytes_total{protocols=\"/ip4/tcp/p2p\",direction=\"Inbound\"} 120
+libp2p_bandwidth_bytes_total{protocols=\"/ip4/tcp/p2p\",direction=\"Outbound\"} 80
+libp2p_bandwidth_bytes_total{protocols=\"/ip4/udp/quic-v1\",direction=\"Inbound\"} 5
+# EOF
+";
+        let transports = parse_bandwidth(text);
+        assert_eq!(transports.len(), 2);
+        let tcp = transports["/ip4/tcp/p2p"];
+        assert_eq!((tcp.inbound, tcp.outbound), (120, 80));
+        let quic = transports["/ip4/udp/quic-v1"];
+        assert_eq!((quic.inbound, quic.outbound), (5, 0));
+    }
+
+    fn message(text: &str) -> Vec<u8> {
+        let mut message = vec![text.len() as u8 + 1];
+        message.extend_from_slice(text.as_bytes());
+        message.push(b'\n');
+        message
+    }
+
+    #[test]
+    fn sniffs_the_accepted_protocol() {
+        let mut listener = message("/multistream/1.0.0");
+        listener.extend(message("na"));
+        assert_eq!(negotiated_protocol(&listener), Sniffed::NeedMore);
+        listener.extend(message("/ipfs/bitswap/1.2.0"));
+        listener.extend_from_slice(b"block data");
+        assert_eq!(
+            negotiated_protocol(&listener),
+            Sniffed::Protocol("/ipfs/bitswap/1.2.0".to_string())
+        );
+        assert_eq!(negotiated_protocol(&[0xff; 16]), Sniffed::NeedMore);
+        assert_eq!(negotiated_protocol(&[0xff, 0x7f]), Sniffed::Unknown);
+    }
+
+    #[test]
+    fn labels_our_protocols() {
+        assert_eq!(protocol_label("/ipfs/bitswap/1.2.0"), "bitswap");
+        assert_eq!(protocol_label("/boxpeer/kad/1.0.0"), "kad");
+        assert_eq!(protocol_label("/ipfs/id/1.0.0"), "identify");
+        assert_eq!(protocol_label("/boxpeer/role/1.0.0"), "/boxpeer/role/1.0.0");
+    }
+
+    #[tokio::test]
+    async fn totals_survive_reopen() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let peer = PeerId::random();
+        let meter = BandwidthMeter::open(&db, BandwidthLimits::default()).unwrap();
+        meter.peer(peer).add(TrafficStats {
+            inbound: 40,
+            outbound: 100,
+        });
+        meter.protocol("bitswap").add(TrafficStats {
+            inbound: 40,
+            outbound: 0,
+        });
+        meter.inner.relayed.add(TrafficStats {
+            inbound: 7,
+            outbound: 3,
+        });
+        meter.persist().unwrap();
+
+        let meter = BandwidthMeter::open(&db, BandwidthLimits::default()).unwrap();
+        assert_eq!(meter.relayed().sum(), 10);
+        let stats = meter.stats();
+        assert_eq!(stats.peers[0].peer_id, peer.to_string());
+        assert_eq!((stats.peers[0].inbound, stats.peers[0].outbound), (40, 100));
+        assert_eq!(stats.protocols[0].protocol, "bitswap");
+        assert_eq!(stats.protocols[0].inbound, 40);
+    }
+
+    #[test]
+    fn quietest_idle_peer_is_forgotten() {
+        let (idle, quiet, connected) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let traffic = |bytes| {
+            Arc::new(Traffic::new(TrafficStats {
+                inbound: bytes,
+                outbound: 0,
+            }))
+        };
+        let mut peers = HashMap::from([
+            (idle, traffic(50)),
+            (quiet, traffic(10)),
+            (connected, traffic(1)),
+        ]);
+        let _open = peers[&connected].clone();
+        forget_quietest(&mut peers);
+        assert!(!peers.contains_key(&quiet));
+        assert!(peers.contains_key(&idle) && peers.contains_key(&connected));
+    }
+}
@@ -0,0 +1,616 @@
+use crate::bandwidth::read_uvarint;
+use crate::store::BitswapStore;
+use beetswap::{QueryId, ToHandlerEvent};
+use cid::Cid;
+use libp2p::core::transport::PortUse;
+use libp2p::core::Endpoint;
+use libp2p::swarm::{
+    ConnectionClosed, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler,
+    THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+
+// How long a peer asked for a block it said it has gets to send it.
+const WANT_TIMEOUT: Duration = Duration::from_secs(60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+// `WantType::Block` in the bitswap protobuf.
+const WANT_BLOCK: i32 = 0;
+// `blockPresences` in a bitswap message, and `BlockPresenceType::DontHave`.
+const PRESENCES_FIELD: u64 = 4;
+const DONT_HAVE: u64 = 1;
+// A presence is a CID and a type, so anything bigger isn't one.
+const MAX_PRESENCE_LEN: u64 = 256;
+
+#[derive(Debug)]
+pub(crate) enum Event {
+    /// `from` is the peer that sent the block and how long after asking it
+    /// arrived, or `None` if we can't tell who sent it.
+    GetQueryResponse {
+        query_id: QueryId,
+        data: Vec<u8>,
+        from: Option<(PeerId, Duration)>,
+    },
+    GetQueryError {
+        query_id: QueryId,
+        error: beetswap::Error,
+    },
+    /// `peer` said it had a block and then didn't send it.
+    WantFailed { peer: PeerId, reason: &'static str },
+}
+
+/// DONT_HAVE replies the bandwidth meter picks off inbound bitswap streams.
+/// Beetswap acts on them without saying who sent them.
+#[derive(Clone, Default)]
+pub(crate) struct DontHaves(Arc<Mutex<Vec<(PeerId, Cid)>>>);
+
+impl DontHaves {
+    pub(crate) fn push(&self, peer: PeerId, cid: Cid) {
+        self.0.lock().unwrap().push((peer, cid));
+    }
+
+    fn take(&self) -> Vec<(PeerId, Cid)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// The want-blocks we've sent and when. Beetswap asks every peer whether it
+/// has a block and only wants the block itself from those that said yes,
+/// so a peer that then doesn't send it has let us down.
+#[derive(Default)]
+struct Wants(HashMap<Cid, HashMap<PeerId, Instant>>);
+
+impl Wants {
+    fn want(&mut self, peer: PeerId, cid: Cid) {
+        self.0
+            .entry(cid)
+            .or_default()
+            .entry(peer)
+            .or_insert_with(Instant::now);
+    }
+
+    /// Drops the want for `cid` from `peer`, saying whether there was one.
+    fn cancel(&mut self, peer: &PeerId, cid: &Cid) -> bool {
+        let Some(peers) = self.0.get_mut(cid) else {
+            return false;
+        };
+        let wanted = peers.remove(peer).is_some();
+        if peers.is_empty() {
+            self.0.remove(cid);
+        }
+        wanted
+    }
+
+    /// Drops whatever `peer` was asked for that a full wantlist no longer
+    /// lists.
+    fn retain(&mut self, peer: &PeerId, listed: &HashSet<Cid>) {
+        self.0.retain(|cid, peers| {
+            if !listed.contains(cid) {
+                peers.remove(peer);
+            }
+            !peers.is_empty()
+        });
+    }
+
+    /// Clears the wants for a block that has arrived. Beetswap doesn't say
+    /// who sent it, so it's only put down to a peer when just one was
+    /// asked for it.
+    fn answered(&mut self, cid: &Cid) -> Option<(PeerId, Duration)> {
+        let peers = self.0.remove(cid)?;
+        if peers.len() != 1 {
+            return None;
+        }
+        peers
+            .into_iter()
+            .next()
+            .map(|(peer, asked)| (peer, asked.elapsed()))
+    }
+
+    /// Clears the wants for a block we've given up on, returning the peers
+    /// that never sent it.
+    fn abandon(&mut self, cid: &Cid) -> Vec<PeerId> {
+        self.0
+            .remove(cid)
+            .map(|peers| peers.into_keys().collect())
+            .unwrap_or_default()
+    }
+
+    /// Clears the wants older than `timeout`, returning who they were to.
+    fn expire(&mut self, timeout: Duration) -> Vec<PeerId> {
+        let mut expired = Vec::new();
+        self.0.retain(|_, peers| {
+            peers.retain(|peer, asked| {
+                let keep = asked.elapsed() < timeout;
+                if !keep {
+                    expired.push(*peer);
+                }
+                keep
+            });
+            !peers.is_empty()
+        });
+        expired
+    }
+
+    fn forget(&mut self, peer: &PeerId) {
+        self.0.retain(|_, peers| {
+            peers.remove(peer);
+            !peers.is_empty()
+        });
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+enum Walk {
+    // The length in front of the next message.
+    #[default]
+    Length,
+    Tag,
+    // The length of a length-delimited field.
+    FieldLength,
+    // The value of a varint field.
+    Value,
+    Skip(u64),
+    Presence(u64),
+    // Not a bitswap message after all; the rest of the stream is ignored.
+    Lost,
+}
+
+/// Walks the length-prefixed bitswap messages read off a stream and picks
+/// out the CIDs of DONT_HAVE presences. Blocks and everything else are
+/// skipped as they go by rather than buffered.
+#[derive(Default)]
+pub(crate) struct PresenceReader {
+    state: Walk,
+    // Bytes left in the message being read.
+    left: u64,
+    varint: u64,
+    shift: u32,
+    // Top level field being read.
+    field: u64,
+    presence: Vec<u8>,
+}
+
+impl PresenceReader {
+    pub(crate) fn feed(&mut self, mut data: &[u8], mut dont_have: impl FnMut(Cid)) {
+        while let Some((&byte, rest)) = data.split_first() {
+            self.state = match self.state {
+                Walk::Lost => return,
+                Walk::Skip(n) | Walk::Presence(n) => {
+                    let taken = n.min(data.len() as u64);
+                    let (chunk, rest) = data.split_at(taken as usize);
+                    data = rest;
+                    // Fields were checked to fit in the message.
+                    self.left -= taken;
+                    match self.state {
+                        Walk::Skip(_) if taken < n => Walk::Skip(n - taken),
+                        Walk::Presence(_) if taken < n => {
+                            self.presence.extend_from_slice(chunk);
+                            Walk::Presence(n - taken)
+                        }
+                        Walk::Presence(_) => {
+                            self.presence.extend_from_slice(chunk);
+                            if let Some(cid) = dont_have_cid(&self.presence) {
+                                dont_have(cid);
+                            }
+                            self.next_field()
+                        }
+                        _ => self.next_field(),
+                    }
+                }
+                state => {
+                    data = rest;
+                    self.read_varint(state, byte)
+                }
+            };
+        }
+    }
+
+    fn read_varint(&mut self, state: Walk, byte: u8) -> Walk {
+        if !matches!(state, Walk::Length) {
+            let Some(left) = self.left.checked_sub(1) else {
+                return Walk::Lost;
+            };
+            self.left = left;
+        }
+        if self.shift >= 64 {
+            return Walk::Lost;
+        }
+        self.varint |= u64::from(byte & 0x7f) << self.shift;
+        if byte & 0x80 != 0 {
+            self.shift += 7;
+            return state;
+        }
+        let value = std::mem::take(&mut self.varint);
+        self.shift = 0;
+        match state {
+            Walk::Length => {
+                self.left = value;
+                self.next_field()
+            }
+            Walk::Tag => {
+                self.field = value >> 3;
+                match value & 7 {
+                    0 => Walk::Value,
+                    1 => self.skip(8),
+                    2 => Walk::FieldLength,
+                    5 => self.skip(4),
+                    _ => Walk::Lost,
+                }
+            }
+            Walk::FieldLength
+                if self.field == PRESENCES_FIELD
+                    && (1..=MAX_PRESENCE_LEN).contains(&value)
+                    && value <= self.left =>
+            {
+                self.presence.clear();
+                Walk::Presence(value)
+            }
+            Walk::FieldLength => self.skip(value),
+            _ => self.next_field(),
+        }
+    }
+
+    fn skip(&self, len: u64) -> Walk {
+        if len > self.left {
+            Walk::Lost
+        } else if len == 0 {
+            self.next_field()
+        } else {
+            Walk::Skip(len)
+        }
+    }
+
+    fn next_field(&self) -> Walk {
+        if self.left == 0 {
+            Walk::Length
+        } else {
+            Walk::Tag
+        }
+    }
+}
+
+/// The CID in a block presence, if the presence is a DONT_HAVE.
+fn dont_have_cid(mut presence: &[u8]) -> Option<Cid> {
+    let mut cid = None;
+    // Have is the default and left out on the wire.
+    let mut kind = 0;
+    while !presence.is_empty() {
+        let (tag, used) = read_uvarint(presence)?;
+        presence = &presence[used..];
+        let (value, used) = read_uvarint(presence)?;
+        presence = &presence[used..];
+        match (tag >> 3, tag & 7) {
+            (1, 2) => {
+                cid = Some(presence.get(..value)?);
+                presence = &presence[value..];
+            }
+            (2, 0) => kind = value as u64,
+            _ => return None,
+        }
+    }
+    if kind != DONT_HAVE {
+        return None;
+    }
+    Cid::try_from(cid?).ok()
+}
+
+/// Beetswap, plus which peer sent each block, which peers let us down and a
+/// list of peers not to ask for blocks. Responses are matched to peers by
+/// the want-blocks in the wantlists we send.
+pub(crate) struct Bitswap {
+    inner: beetswap::Behaviour<64, BitswapStore>,
+    // Scored too low to ask. They can still fetch from us.
+    shunned: HashSet<PeerId>,
+    queries: HashMap<QueryId, Cid>,
+    wants: Wants,
+    dont_haves: DontHaves,
+    failed: VecDeque<(PeerId, &'static str)>,
+    // Created on first poll, which is inside the runtime.
+    sweep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bitswap {
+    pub(crate) fn new(store: Arc<BitswapStore>, dont_haves: DontHaves) -> Self {
+        Self {
+            inner: beetswap::Behaviour::new(store),
+            shunned: HashSet::new(),
+            queries: HashMap::new(),
+            wants: Wants::default(),
+            dont_haves,
+            failed: VecDeque::new(),
+            sweep: None,
+        }
+    }
+
+    pub(crate) fn get(&mut self, cid: &Cid) -> QueryId {
+        let query_id = self.inner.get(cid);
+        self.queries.insert(query_id, *cid);
+        query_id
+    }
+
+    /// Cancels a query we've given up on. Once no query wants its block,
+    /// the peers that said they had it and never sent it are reported.
+    pub(crate) fn cancel(&mut self, query_id: QueryId) {
+        self.inner.cancel(query_id);
+        let Some(cid) = self.queries.remove(&query_id) else {
+            return;
+        };
+        if !self.queries.values().any(|wanted| *wanted == cid) {
+            for peer in self.wants.abandon(&cid) {
+                self.failed
+                    .push_back((peer, "Didn't send blocks it said it had"));
+            }
+        }
+    }
+
+    /// Stops asking `peer` for blocks. Beetswap gives up on a peer whose
+    /// wantlists go unsent, so it stays out until it reconnects even if
+    /// it's let back in sooner.
+    pub(crate) fn shun(&mut self, peer: PeerId) {
+        self.shunned.insert(peer);
+    }
+
+    pub(crate) fn unshun(&mut self, peer: &PeerId) {
+        self.shunned.remove(peer);
+    }
+
+    fn attribute(&mut self, event: beetswap::Event) -> Event {
+        match event {
+            beetswap::Event::GetQueryResponse { query_id, data } => {
+                let from = self
+                    .queries
+                    .remove(&query_id)
+                    .and_then(|cid| self.wants.answered(&cid));
+                Event::GetQueryResponse {
+                    query_id,
+                    data,
+                    from,
+                }
+            }
+            beetswap::Event::GetQueryError { query_id, error } => {
+                self.queries.remove(&query_id);
+                Event::GetQueryError { query_id, error }
+            }
+        }
+    }
+
+    /// Collects the DONT_HAVEs to want-blocks and the want-blocks that have
+    /// gone unanswered for too long.
+    fn find_failures(&mut self, cx: &mut Context<'_>) {
+        for (peer, cid) in self.dont_haves.take() {
+            if self.wants.cancel(&peer, &cid) {
+                self.failed
+                    .push_back((peer, "Said it had blocks, then that it didn't"));
+            }
+        }
+        let sweep = self
+            .sweep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(SWEEP_INTERVAL)));
+        while sweep.as_mut().poll(cx).is_ready() {
+            for peer in self.wants.expire(WANT_TIMEOUT) {
+                self.failed
+                    .push_back((peer, "Didn't send blocks it said it had in time"));
+            }
+            sweep
+                .as_mut()
+                .reset(tokio::time::Instant::now() + SWEEP_INTERVAL);
+        }
+    }
+}
+
+impl NetworkBehaviour for Bitswap {
+    type ConnectionHandler = THandler<beetswap::Behaviour<64, BitswapStore>>;
+    type ToSwarm = Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+        port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+            port_use,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        if let FromSwarm::ConnectionClosed(ConnectionClosed {
+            peer_id,
+            remaining_established: 0,
+            ..
+        }) = event
+        {
+            self.wants.forget(&peer_id);
+        }
+        self.inner.on_swarm_event(event);
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event);
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Event, THandlerInEvent<Self>>> {
+        self.find_failures(cx);
+        if let Some((peer, reason)) = self.failed.pop_front() {
+            return Poll::Ready(ToSwarm::GenerateEvent(Event::WantFailed { peer, reason }));
+        }
+        loop {
+            let event = futures::ready!(self.inner.poll(cx));
+            if let ToSwarm::NotifyHandler {
+                peer_id,
+                event: ToHandlerEvent::SendWantlist(wantlist),
+                ..
+            } = &event
+            {
+                if self.shunned.contains(peer_id) {
+                    continue;
+                }
+                let mut listed = HashSet::new();
+                for entry in &wantlist.entries {
+                    let Ok(cid) = Cid::try_from(entry.block.as_slice()) else {
+                        continue;
+                    };
+                    if entry.cancel {
+                        self.wants.cancel(peer_id, &cid);
+                    } else if entry.wantType as i32 == WANT_BLOCK {
+                        self.wants.want(*peer_id, cid);
+                        listed.insert(cid);
+                    }
+                }
+                if wantlist.full {
+                    self.wants.retain(peer_id, &listed);
+                }
+            }
+            return Poll::Ready(event.map_out(|event| self.attribute(event)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::Multihash;
+
+    fn cid(n: u8) -> Cid {
+        Cid::new_v1(0x55, Multihash::wrap(0x00, &[n]).unwrap())
+    }
+
+    fn field(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut field = vec![tag, value.len() as u8];
+        field.extend_from_slice(value);
+        field
+    }
+
+    fn presence(cid: &Cid, dont_have: bool) -> Vec<u8> {
+        let mut presence = field(0x0a, &cid.to_bytes());
+        if dont_have {
+            presence.extend_from_slice(&[0x10, 0x01]);
+        }
+        field(0x22, &presence)
+    }
+
+    fn message(fields: &[Vec<u8>]) -> Vec<u8> {
+        let body = fields.concat();
+        let mut message = vec![body.len() as u8];
+        message.extend(body);
+        message
+    }
+
+    #[test]
+    fn reads_dont_haves_across_messages() {
+        let stream = [
+            message(&[
+                field(0x1a, &[0xaa; 40]),
+                presence(&cid(1), false),
+                presence(&cid(2), true),
+                vec![0x28, 0x05],
+            ]),
+            message(&[presence(&cid(3), true)]),
+        ]
+        .concat();
+
+        let mut found = Vec::new();
+        PresenceReader::default().feed(&stream, |cid| found.push(cid));
+        assert_eq!(found, vec![cid(2), cid(3)]);
+
+        let mut found = Vec::new();
+        let mut reader = PresenceReader::default();
+        for byte in &stream {
+            reader.feed(std::slice::from_ref(byte), |cid| found.push(cid));
+        }
+        assert_eq!(found, vec![cid(2), cid(3)]);
+    }
+
+    #[test]
+    fn stops_at_fields_that_overrun_the_message() {
+        let mut stream = message(&[vec![0x1a, 0x7f]]);
+        stream.extend(message(&[presence(&cid(1), true)]));
+        let mut found = Vec::new();
+        PresenceReader::default().feed(&stream, |cid| found.push(cid));
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn blocks_are_put_down_to_the_only_peer_asked() {
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let mut wants = Wants::default();
+        wants.want(first, cid(1));
+        wants.want(first, cid(2));
+        wants.want(second, cid(2));
+        assert_eq!(wants.answered(&cid(1)).map(|(peer, _)| peer), Some(first));
+        assert_eq!(wants.answered(&cid(2)), None);
+        assert!(wants.0.is_empty());
+    }
+
+    #[test]
+    fn cancelled_wants_dont_fail() {
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let mut wants = Wants::default();
+        wants.want(first, cid(1));
+        wants.want(second, cid(1));
+        wants.want(second, cid(2));
+        assert!(wants.cancel(&first, &cid(1)));
+        assert!(!wants.cancel(&first, &cid(1)));
+        wants.retain(&second, &HashSet::from([cid(2)]));
+        assert_eq!(wants.expire(Duration::ZERO), vec![second]);
+        assert!(wants.0.is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+use crate::storage::block_matches_cid;
+use crate::store::NodeBlockstore;
+use anyhow::{anyhow, Context, Result};
+use blockstore::Blockstore;
+use cid::Cid;
+use libipld::cbor::DagCborCodec;
+use libipld::codec::Codec;
+use libipld::{Ipld, IpldCodec};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tracing::warn;
+
+const RAW_CODEC: u64 = 0x55;
+// Guards against reading absurd section lengths from a corrupt file.
+const MAX_SECTION_LEN: u64 = 1 << 32;
+
+/// Writes `root` and every block reachable from it as a CARv1 file.
+pub(crate) async fn export_car(blockstore: &NodeBlockstore, root: Cid, path: &Path) -> Result<u64> {
+    let mut writer = BufWriter::new(File::create(path).await?);
+
+    let header = car_header(&[root])?;
+    write_varint(&mut writer, header.len() as u64).await?;
+    writer.write_all(&header).await?;
+
+    let mut written = 0;
+    let mut seen = HashSet::new();
+    let mut stack = vec![root];
+    while let Some(cid) = stack.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        let data = blockstore
+            .get(&cid)
+            .await
+            .map_err(|e| anyhow!("Failed to read block {}: {:?}", cid, e))?
+            .ok_or_else(|| anyhow!("Block {} is not stored locally", cid))?;
+
+        let cid_bytes = cid.to_bytes();
+        write_varint(&mut writer, (cid_bytes.len() + data.len()) as u64).await?;
+        writer.write_all(&cid_bytes).await?;
+        writer.write_all(&data).await?;
+        written += 1;
+
+        stack.extend(links(&cid, &data)?);
+    }
+
+    writer.flush().await?;
+    Ok(written)
+}
+
+/// Reads a CARv1 file, checking every block against its CID before storing
+/// it. Returns the roots listed in the header. Nothing is kept unless the
+/// whole file imports cleanly.
+pub(crate) async fn import_car(blockstore: &NodeBlockstore, path: &Path) -> Result<Vec<Cid>> {
+    // Check every block first so a bad one half-way through doesn't leave a
+    // partial import behind.
+    let (mut car, roots) = CarReader::open(path).await?;
+    while car.next_block().await?.is_some() {}
+
+    // The file could still change or the store fill up between the passes,
+    // so undo whatever we added if this one fails.
+    let mut added = Vec::new();
+    let result = store_blocks(blockstore, path, &mut added).await;
+    if result.is_err() {
+        for cid in added {
+            if let Err(e) = blockstore.remove(&cid).await {
+                warn!("Failed to roll back block {}: {:?}", cid, e);
+            }
+        }
+    }
+    result.map(|_| roots)
+}
+
+async fn store_blocks(
+    blockstore: &NodeBlockstore,
+    path: &Path,
+    added: &mut Vec<Cid>,
+) -> Result<()> {
+    let (mut car, _) = CarReader::open(path).await?;
+    while let Some((cid, data)) = car.next_block().await? {
+        if blockstore.has(&cid).await? {
+            continue;
+        }
+        blockstore
+            .put_keyed(&cid, &data)
+            .await
+            .map_err(|e| blockstore.put_error(e, data.len() as u64))
+            .with_context(|| format!("Failed to store block {}", cid))?;
+        added.push(cid);
+    }
+    Ok(())
+}
+
+struct CarReader<R> {
+    reader: R,
+}
+
+impl CarReader<BufReader<File>> {
+    /// Opens a CARv1 file and reads its header, returning the listed roots.
+    async fn open(path: &Path) -> Result<(Self, Vec<Cid>)> {
+        let mut reader = BufReader::new(File::open(path).await?);
+        let header_len = read_varint(&mut reader)
+            .await?
+            .ok_or_else(|| anyhow!("CAR file is empty"))?;
+        let header = read_exact(&mut reader, header_len).await?;
+        let roots = parse_header(&header)?;
+        Ok((Self { reader }, roots))
+    }
+}
+
+impl<R: AsyncRead + Unpin> CarReader<R> {
+    /// Returns the next block, or an error if it doesn't match its CID.
+    async fn next_block(&mut self) -> Result<Option<(Cid, Vec<u8>)>> {
+        let Some(section_len) = read_varint(&mut self.reader).await? else {
+            return Ok(None);
+        };
+        let mut section = read_exact(&mut self.reader, section_len).await?;
+        let mut cursor = std::io::Cursor::new(section.as_slice());
+        let cid = Cid::read_bytes(&mut cursor).map_err(|e| anyhow!("Invalid CID in CAR: {}", e))?;
+        let data = section.split_off(cursor.position() as usize);
+
+        if !block_matches_cid(&cid, &data) {
+            return Err(anyhow!("Block {} in CAR does not match its CID", cid));
+        }
+        Ok(Some((cid, data)))
+    }
+}
+
+fn car_header(roots: &[Cid]) -> Result<Vec<u8>> {
+    let roots = roots
+        .iter()
+        .map(|root| Ok(Ipld::Link(libipld::Cid::try_from(root.to_bytes())?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut header = BTreeMap::new();
+    header.insert("roots".to_string(), Ipld::List(roots));
+    header.insert("version".to_string(), Ipld::Integer(1));
+    DagCborCodec.encode(&Ipld::Map(header))
+}
+
+fn parse_header(bytes: &[u8]) -> Result<Vec<Cid>> {
+    let header: Ipld = DagCborCodec.decode(bytes)?;
+    match header.get("version")? {
+        Ipld::Integer(1) => {}
+        version => return Err(anyhow!("Unsupported CAR version: {:?}", version)),
+    }
+    match header.get("roots")? {
+        Ipld::List(roots) => roots
+            .iter()
+            .map(|root| match root {
+                Ipld::Link(cid) => Ok(Cid::try_from(cid.to_bytes())?),
+                other => Err(anyhow!("Invalid CAR root: {:?}", other)),
+            })
+            .collect(),
+        other => Err(anyhow!("Invalid CAR roots: {:?}", other)),
+    }
+}
+
+/// Returns the CIDs a block links to. Raw blocks, which is what BoxPeer
+/// stores for uploaded files, have none.
+pub(crate) fn links(cid: &Cid, data: &[u8]) -> Result<Vec<Cid>> {
+    if cid.codec() == RAW_CODEC {
+        return Ok(Vec::new());
+    }
+    let codec = IpldCodec::try_from(cid.codec())?;
+    let ipld: Ipld = codec.decode(data)?;
+    let mut links = Vec::new();
+    ipld.references(&mut links);
+    links
+        .into_iter()
+        .map(|link| Ok(Cid::try_from(link.to_bytes())?))
+        .collect()
+}
+
+async fn write_varint<W: AsyncWrite + Unpin>(writer: &mut W, mut value: u64) -> Result<()> {
+    let mut buf = Vec::with_capacity(10);
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Reads an unsigned LEB128 varint, returning `None` on a clean end of file.
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<u64>> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && shift == 0 => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(anyhow!("Varint in CAR file is too long"))
+}
+
+async fn read_exact<R: AsyncRead + Unpin>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    if len > MAX_SECTION_LEN {
+        return Err(anyhow!("CAR section of {} bytes is too large", len));
+    }
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BlockstoreConfig;
+    use multihash_codetable::{Code, MultihashDigest};
+
+    const DAG_CBOR_CODEC: u64 = 0x71;
+
+    async fn memory_store() -> NodeBlockstore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        NodeBlockstore::open(
+            &BlockstoreConfig::Memory,
+            &db,
+            &std::env::temp_dir(),
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn raw_block(data: &[u8]) -> (Cid, Vec<u8>) {
+        (
+            Cid::new_v1(RAW_CODEC, Code::Sha2_256.digest(data)),
+            data.to_vec(),
+        )
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("boxpeer-{}-{}.car", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn varint_roundtrip() {
+        for value in [0, 1, 127, 128, 300, 16_384, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value).await.unwrap();
+            let mut reader = buf.as_slice();
+            assert_eq!(read_varint(&mut reader).await.unwrap(), Some(value));
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn varint_clean_eof_is_none() {
+        let mut reader: &[u8] = &[];
+        assert_eq!(read_varint(&mut reader).await.unwrap(), None);
+        let mut truncated: &[u8] = &[0x80];
+        assert!(read_varint(&mut truncated).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn export_import_roundtrip() {
+        let (leaf_a, data_a) = raw_block(b"first leaf");
+        let (leaf_b, data_b) = raw_block(b"second leaf");
+        let links = Ipld::List(vec![
+            Ipld::Link(libipld::Cid::try_from(leaf_a.to_bytes()).unwrap()),
+            Ipld::Link(libipld::Cid::try_from(leaf_b.to_bytes()).unwrap()),
+        ]);
+        let root_data = DagCborCodec.encode(&links).unwrap();
+        let root = Cid::new_v1(DAG_CBOR_CODEC, Code::Sha2_256.digest(&root_data));
+
+        let source = memory_store().await;
+        for (cid, data) in [(root, &root_data), (leaf_a, &data_a), (leaf_b, &data_b)] {
+            source.put_keyed(&cid, data).await.unwrap();
+        }
+        let path = temp_path("roundtrip");
+        assert_eq!(export_car(&source, root, &path).await.unwrap(), 3);
+
+        let target = memory_store().await;
+        let roots = import_car(&target, &path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(roots, vec![root]);
+        assert_eq!(target.get(&root).await.unwrap(), Some(root_data));
+        assert_eq!(target.get(&leaf_a).await.unwrap(), Some(data_a));
+        assert_eq!(target.get(&leaf_b).await.unwrap(), Some(data_b));
+    }
+
+    #[tokio::test]
+    async fn corrupt_block_imports_nothing() {
+        let (good, good_data) = raw_block(b"good block");
+        let (bad, _) = raw_block(b"bad block");
+
+        let path = temp_path("corrupt");
+        let mut writer = BufWriter::new(File::create(&path).await.unwrap());
+        let header = car_header(&[good]).unwrap();
+        write_varint(&mut writer, header.len() as u64)
+            .await
+            .unwrap();
+        writer.write_all(&header).await.unwrap();
+        for (cid, data) in [(good, &good_data[..]), (bad, &b"tampered"[..])] {
+            let cid_bytes = cid.to_bytes();
+            write_varint(&mut writer, (cid_bytes.len() + data.len()) as u64)
+                .await
+                .unwrap();
+            writer.write_all(&cid_bytes).await.unwrap();
+            writer.write_all(data).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+
+        let store = memory_store().await;
+        let result = import_car(&store, &path).await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+        assert!(!store.has(&good).await.unwrap());
+        assert!(!store.has(&bad).await.unwrap());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::node::NodeType;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tauri::api::path::cache_dir;
+use tracing::warn;
+
+const CONFIG_FILE: &str = "config.json";
+// Only reachable over QUIC, so it's no use on a private network.
+const DEFAULT_BOOTSTRAP: &str = "/ip4/203.161.57.50/udp/9090/quic-v1";
+// Used when the OS can't find us a free port pair on first launch.
+const FALLBACK_LISTEN_PORT: u16 = 9090;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BlockstoreConfig {
+    #[default]
+    Sled,
+    Memory,
+    FlatFile {
+        // Defaults to `<cache>/Boxpeer/blocks` when not set.
+        path: Option<PathBuf>,
+    },
+}
+
+/// Addresses to listen on for each transport. Leaving a list empty disables
+/// listening on that transport; we can still dial out over it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TransportConfig {
+    // Port used by listen addresses that ask for port 0, so the node keeps
+    // the same port across launches and it can be forwarded. Picked on first
+    // launch. WebSocket listens on the port after it.
+    pub listen_port: Option<u16>,
+    // Ask the router to forward our listen ports over UPnP.
+    pub upnp: bool,
+    pub quic: Vec<Multiaddr>,
+    pub tcp: Vec<Multiaddr>,
+    pub websocket: Vec<Multiaddr>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: None,
+            upnp: true,
+            quic: vec![
+                "/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap(),
+                "/ip6/::/udp/0/quic-v1".parse().unwrap(),
+            ],
+            tcp: vec![
+                "/ip4/0.0.0.0/tcp/0".parse().unwrap(),
+                "/ip6/::/tcp/0".parse().unwrap(),
+            ],
+            websocket: vec![
+                "/ip4/0.0.0.0/tcp/0/ws".parse().unwrap(),
+                "/ip6/::/tcp/0/ws".parse().unwrap(),
+            ],
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Listen addresses with port 0 replaced by the configured port.
+    pub(crate) fn listen_addresses(&self, quic: bool) -> Vec<Multiaddr> {
+        let quic: &[Multiaddr] = if quic { &self.quic } else { &[] };
+        let websocket_port = self.listen_port.and_then(|port| port.checked_add(1));
+        quic.iter()
+            .chain(&self.tcp)
+            .map(|address| with_port(address, self.listen_port))
+            .chain(
+                self.websocket
+                    .iter()
+                    .map(|address| with_port(address, websocket_port)),
+            )
+            .collect()
+    }
+}
+
+/// Swaps a port 0 in `address` for `port`, leaving explicit ports alone.
+fn with_port(address: &Multiaddr, port: Option<u16>) -> Multiaddr {
+    let Some(port) = port else {
+        return address.clone();
+    };
+    address
+        .iter()
+        .map(|protocol| match protocol {
+            Protocol::Tcp(0) => Protocol::Tcp(port),
+            Protocol::Udp(0) => Protocol::Udp(port),
+            protocol => protocol,
+        })
+        .collect()
+}
+
+/// Asks the OS for a free port whose UDP port and next TCP port are free
+/// too, so QUIC, TCP and WebSocket can all use it.
+fn pick_listen_port() -> u16 {
+    for _ in 0..16 {
+        let Ok(port) = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|listener| listener.local_addr())
+            .map(|address| address.port())
+        else {
+            break;
+        };
+        let Some(websocket_port) = port.checked_add(1) else {
+            continue;
+        };
+        if UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+            && TcpListener::bind((Ipv4Addr::UNSPECIFIED, websocket_port)).is_ok()
+        {
+            return port;
+        }
+    }
+    FALLBACK_LISTEN_PORT
+}
+
+/// Circuit Relay v2 server settings for publicly reachable nodes. Defaults
+/// match libp2p's own except that serving as a relay is opt-in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RelayServerConfig {
+    pub enabled: bool,
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration_secs: u64,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub max_circuit_duration_secs: u64,
+    pub max_circuit_bytes: u64,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration_secs: 60 * 60,
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration_secs: 2 * 60,
+            max_circuit_bytes: 1 << 17,
+        }
+    }
+}
+
+/// Caps on how many connections the swarm keeps open. `None` means no limit.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConnectionLimitsConfig {
+    pub max_established: Option<u32>,
+    pub max_established_per_peer: Option<u32>,
+    pub max_pending_incoming: Option<u32>,
+    pub max_pending_outgoing: Option<u32>,
+    // Refuse new connections once the process uses this fraction of system memory.
+    pub max_memory_fraction: Option<f64>,
+    // Close connections that have had no open streams, e.g. no bitswap wants, for this long.
+    pub idle_connection_timeout_secs: u64,
+}
+
+impl Default for ConnectionLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_established: Some(256),
+            max_established_per_peer: Some(4),
+            max_pending_incoming: Some(64),
+            max_pending_outgoing: Some(64),
+            max_memory_fraction: Some(0.8),
+            idle_connection_timeout_secs: 60,
+        }
+    }
+}
+
+/// Token bucket rates for bitswap traffic, in bytes per second. Upload covers
+/// the blocks we serve and download the blocks we fetch, both in total and
+/// per peer. `None` means no limit.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+pub struct BandwidthLimits {
+    pub upload_bytes_per_sec: Option<u64>,
+    pub download_bytes_per_sec: Option<u64>,
+    pub peer_upload_bytes_per_sec: Option<u64>,
+    pub peer_download_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct NodeConfig {
+    // Role advertised to other peers, which also decides the DHT mode and
+    // whether we serve blocks.
+    pub node_type: NodeType,
+    // Serve blocks over bitswap regardless of role. By default consumers
+    // don't and everyone else does.
+    pub serve_blocks: Option<bool>,
+    pub blockstore: BlockstoreConfig,
+    // Upper bound on stored block bytes; unlimited when not set.
+    pub storage_quota_bytes: Option<u64>,
+    // Encrypt blocks on disk with a key derived from the node keypair.
+    pub encrypt_at_rest: bool,
+    pub transports: TransportConfig,
+    // Circuit relays to reserve a slot on when we aren't publicly reachable.
+    // Each address must end in `/p2p/<relay peer id>`.
+    pub relays: Vec<Multiaddr>,
+    pub relay_server: RelayServerConfig,
+    pub connection_limits: ConnectionLimitsConfig,
+    pub bandwidth_limits: BandwidthLimits,
+    // Swarm key file for a private network. Only peers holding the same key
+    // can connect, and QUIC is disabled since it can't be protected.
+    pub swarm_key_path: Option<PathBuf>,
+    // Also speak the pre-1.0 `/ipfs/0.1.0` DHT protocol, for public-network
+    // nodes that haven't upgraded. Goes away in protocol version 2.
+    pub legacy_protocol: bool,
+    // Peers dialled on startup. Left empty, public networks use the Boxpeer
+    // bootstrap node; private networks can't reach it and must list their own.
+    pub bootstrap: Vec<Multiaddr>,
+}
+
+impl NodeConfig {
+    pub(crate) fn bootstrap_peers(&self) -> Vec<Multiaddr> {
+        if self.bootstrap.is_empty() && self.swarm_key_path.is_none() {
+            vec![DEFAULT_BOOTSTRAP.parse().expect("valid bootstrap address")]
+        } else {
+            self.bootstrap.clone()
+        }
+    }
+}
+
+// Serialises read-modify-write cycles on the config file.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+pub(crate) fn config_path() -> PathBuf {
+    let mut path = PathBuf::from(cache_dir().unwrap());
+    path.push("Boxpeer");
+    path.push(CONFIG_FILE);
+    path
+}
+
+/// Reads the node config from the Boxpeer cache directory, writing the
+/// defaults there on first launch so users have a file to edit. The listen
+/// port is picked and saved the first time it's missing.
+pub(crate) fn load_config() -> NodeConfig {
+    let path = config_path();
+    let mut config = match fs::read(&path) {
+        Ok(contents) => match serde_json::from_slice(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Invalid config at {:?}, using defaults: {:?}", path, e);
+                // Don't overwrite a file the user may want to fix.
+                return NodeConfig::default();
+            }
+        },
+        Err(_) => NodeConfig::default(),
+    };
+    if config.transports.listen_port.is_none() {
+        config.transports.listen_port = Some(pick_listen_port());
+        if let Err(e) = save_config(&config) {
+            warn!("Failed to write config to {:?}: {:?}", path, e);
+        }
+    }
+    config
+}
+
+/// Changes one setting in the saved config, leaving the rest as the user
+/// wrote it. Refuses when the file is there but doesn't parse, since saving
+/// would swap their edits for the defaults we fell back to.
+pub(crate) fn update_config(update: impl FnOnce(&mut NodeConfig)) -> io::Result<()> {
+    let _guard = CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = config_path();
+    let mut config = match fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Fix the config at {:?} before changing settings: {}",
+                    path, e
+                ),
+            )
+        })?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => NodeConfig::default(),
+        Err(e) => return Err(e),
+    };
+    update(&mut config);
+    save_config(&config)
+}
+
+pub(crate) fn save_config(config: &NodeConfig) -> std::io::Result<()> {
+    let path = config_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = serde_json::to_vec_pretty(config)?;
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn with_port_only_replaces_port_zero() {
+        assert_eq!(
+            with_port(&addr("/ip4/0.0.0.0/udp/0/quic-v1"), Some(4001)),
+            addr("/ip4/0.0.0.0/udp/4001/quic-v1")
+        );
+        assert_eq!(
+            with_port(&addr("/ip6/::/tcp/0/ws"), Some(4001)),
+            addr("/ip6/::/tcp/4001/ws")
+        );
+        assert_eq!(
+            with_port(&addr("/ip4/0.0.0.0/tcp/9000"), Some(4001)),
+            addr("/ip4/0.0.0.0/tcp/9000")
+        );
+        assert_eq!(
+            with_port(&addr("/ip4/0.0.0.0/tcp/0"), None),
+            addr("/ip4/0.0.0.0/tcp/0")
+        );
+    }
+
+    #[test]
+    fn websocket_listens_on_the_next_port() {
+        let transports = TransportConfig {
+            listen_port: Some(4001),
+            ..Default::default()
+        };
+        assert_eq!(
+            transports.listen_addresses(true),
+            vec![
+                addr("/ip4/0.0.0.0/udp/4001/quic-v1"),
+                addr("/ip6/::/udp/4001/quic-v1"),
+                addr("/ip4/0.0.0.0/tcp/4001"),
+                addr("/ip6/::/tcp/4001"),
+                addr("/ip4/0.0.0.0/tcp/4002/ws"),
+                addr("/ip6/::/tcp/4002/ws"),
+            ]
+        );
+    }
+
+    #[test]
+    fn quic_can_be_left_out() {
+        let transports = TransportConfig {
+            listen_port: Some(4001),
+            ..Default::default()
+        };
+        let addresses = transports.listen_addresses(false);
+        assert_eq!(addresses.len(), 4);
+        assert!(!addresses
+            .iter()
+            .any(|address| address.iter().any(|p| p == Protocol::QuicV1)));
+    }
+
+    #[test]
+    fn last_port_has_no_websocket_port() {
+        let transports = TransportConfig {
+            listen_port: Some(u16::MAX),
+            ..Default::default()
+        };
+        let addresses = transports.listen_addresses(false);
+        assert!(addresses.contains(&addr("/ip4/0.0.0.0/tcp/65535")));
+        assert!(addresses.contains(&addr("/ip4/0.0.0.0/tcp/0/ws")));
+    }
+}
@@ -0,0 +1,154 @@
+use futures::channel::{mpsc, oneshot};
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use std::fmt;
+
+/// Errors returned by `P2PCDNClient` and the Tauri commands. The frontend
+/// gets `{ "kind": ..., "message": ... }` so it can branch on `kind` and
+/// still have something readable to show.
+#[derive(Debug, Clone)]
+pub enum BoxPeerError {
+    /// Nobody on the network has the content.
+    NotFound(String),
+    Timeout(String),
+    InvalidCid(String),
+    /// A peer ID, address or other argument that doesn't parse.
+    InvalidInput(String),
+    StorageFull {
+        used: u64,
+        quota: u64,
+        needed: u64,
+    },
+    NotConnected(String),
+    /// The network task isn't running, e.g. while shutting down.
+    NetworkUnavailable,
+    /// The request was dropped before it got an answer.
+    Cancelled,
+    Network(String),
+    Storage(String),
+    Internal(String),
+}
+
+pub type Result<T, E = BoxPeerError> = std::result::Result<T, E>;
+
+impl BoxPeerError {
+    fn kind(&self) -> &'static str {
+        match self {
+            BoxPeerError::NotFound(_) => "NotFound",
+            BoxPeerError::Timeout(_) => "Timeout",
+            BoxPeerError::InvalidCid(_) => "InvalidCid",
+            BoxPeerError::InvalidInput(_) => "InvalidInput",
+            BoxPeerError::StorageFull { .. } => "StorageFull",
+            BoxPeerError::NotConnected(_) => "NotConnected",
+            BoxPeerError::NetworkUnavailable => "NetworkUnavailable",
+            BoxPeerError::Cancelled => "Cancelled",
+            BoxPeerError::Network(_) => "Network",
+            BoxPeerError::Storage(_) => "Storage",
+            BoxPeerError::Internal(_) => "Internal",
+        }
+    }
+}
+
+impl fmt::Display for BoxPeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BoxPeerError::NotFound(what) => write!(f, "Not found: {}", what),
+            BoxPeerError::Timeout(what) => write!(f, "Timed out: {}", what),
+            BoxPeerError::InvalidCid(e) => write!(f, "Invalid CID: {}", e),
+            BoxPeerError::InvalidInput(e) => write!(f, "{}", e),
+            BoxPeerError::StorageFull { quota: 0, .. } => write!(f, "Out of storage space"),
+            BoxPeerError::StorageFull {
+                used,
+                quota,
+                needed,
+            } => write!(
+                f,
+                "Storage quota exceeded: {} of {} bytes used, {} more needed",
+                used, quota, needed
+            ),
+            BoxPeerError::NotConnected(peer) => write!(f, "Not connected to {}", peer),
+            BoxPeerError::NetworkUnavailable => write!(f, "The network isn't running"),
+            BoxPeerError::Cancelled => write!(f, "The request was cancelled"),
+            BoxPeerError::Network(e) => write!(f, "Network error: {}", e),
+            BoxPeerError::Storage(e) => write!(f, "Storage error: {}", e),
+            BoxPeerError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BoxPeerError {}
+
+impl Serialize for BoxPeerError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("kind", self.kind())?;
+        map.serialize_entry("message", &self.to_string())?;
+        if let BoxPeerError::StorageFull {
+            used,
+            quota,
+            needed,
+        } = self
+        {
+            map.serialize_entry("used", used)?;
+            map.serialize_entry("quota", quota)?;
+            map.serialize_entry("needed", needed)?;
+        }
+        map.end()
+    }
+}
+
+/// The event loop and storage code use `anyhow` and wrap a `BoxPeerError`
+/// when the kind matters, so unwrap that before falling back to `Internal`.
+impl From<anyhow::Error> for BoxPeerError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<BoxPeerError>() {
+            Ok(e) => e,
+            Err(e) => BoxPeerError::Internal(format!("{:#}", e)),
+        }
+    }
+}
+
+impl From<mpsc::SendError> for BoxPeerError {
+    fn from(_: mpsc::SendError) -> Self {
+        BoxPeerError::NetworkUnavailable
+    }
+}
+
+impl From<oneshot::Canceled> for BoxPeerError {
+    fn from(_: oneshot::Canceled) -> Self {
+        BoxPeerError::Cancelled
+    }
+}
+
+impl From<cid::Error> for BoxPeerError {
+    fn from(e: cid::Error) -> Self {
+        BoxPeerError::InvalidCid(e.to_string())
+    }
+}
+
+impl From<blockstore::Error> for BoxPeerError {
+    fn from(e: blockstore::Error) -> Self {
+        match e {
+            // What `NodeBlockstore` returns for puts past the storage quota
+            // or onto a full disk. `NodeBlockstore::put_error` knows the sizes.
+            blockstore::Error::ValueTooLarge => BoxPeerError::StorageFull {
+                used: 0,
+                quota: 0,
+                needed: 0,
+            },
+            e => BoxPeerError::Storage(e.to_string()),
+        }
+    }
+}
+
+impl From<sled::Error> for BoxPeerError {
+    fn from(e: sled::Error) -> Self {
+        BoxPeerError::Storage(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for BoxPeerError {
+    fn from(e: std::io::Error) -> Self {
+        BoxPeerError::Storage(e.to_string())
+    }
+}
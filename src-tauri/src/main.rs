@@ -3,127 +3,413 @@
     windows_subsystem = "windows"
 )]
 
+mod announce;
+mod bandwidth;
+mod bitswap;
+mod car;
+mod config;
+mod error;
 mod net;
+mod network;
 mod node;
-use crate::net::P2PCDNClient;
+mod scoring;
+mod storage;
+mod store;
+mod throttle;
+mod transport;
+use crate::announce::Announcement;
+use crate::bandwidth::BandwidthStats;
+use crate::config::BandwidthLimits;
+use crate::error::BoxPeerError;
+use crate::net::{NatInfo, P2PCDNClient, PeerTransports, RelayStats};
+use crate::node::{NodeType, PeerDetails, PeerInfo};
+use crate::scoring::BannedPeer;
+use crate::storage::{ScrubReport, StorageHealth, StorageStats};
 use anyhow::Result;
 use cid::Cid;
+use futures::StreamExt;
 use libp2p::{kad, multiaddr::Multiaddr};
 use libp2p_core::PeerId;
+use serde::Serialize;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tauri::{async_runtime::spawn, State};
+use tauri::{async_runtime::spawn, AppHandle, Manager, RunEvent, State, Window};
 use tokio::sync::Mutex as AsyncMutex;
-use tracing::Level;
+use tracing::{warn, Level};
 
 struct AppState {
-    client: Arc<AsyncMutex<P2PCDNClient>>,
+    client: P2PCDNClient,
     network_events: Arc<AsyncMutex<Pin<Box<dyn futures::Stream<Item = kad::Event> + Send>>>>,
+    shutdown_started: Arc<AtomicBool>,
+    shutdown_done: Arc<AtomicBool>,
 }
 
+/// Shuts the node down once, then exits the app.
+fn begin_shutdown(app: AppHandle) {
+    let (client, done) = {
+        let state = app.state::<AppState>();
+        if state.shutdown_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        (state.client.clone(), state.shutdown_done.clone())
+    };
+    spawn(async move {
+        if let Err(e) = client.shutdown().await {
+            warn!("Node didn't shut down cleanly: {:?}", e);
+        }
+        done.store(true, Ordering::SeqCst);
+        app.exit(0);
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_sigterm() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(e) => {
+            warn!("Can't listen for SIGTERM: {:?}", e);
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_sigterm() {
+    futures::future::pending::<()>().await;
+}
+
+/// The node already listens on its configured addresses at startup, so this
+/// only opens an extra listener when given an address. Returns our peer ID.
 #[tauri::command]
-async fn start_listening(state: State<'_, AppState>) -> Result<String, String> {
-    let address: Multiaddr = "/ip4/0.0.0.0/udp/0/quic-v1"
-        .parse()
-        .expect("Error with address");
+async fn start_listening(
+    state: State<'_, AppState>,
+    multiaddr: Option<String>,
+) -> Result<String, BoxPeerError> {
+    match multiaddr {
+        Some(multiaddr) => {
+            let address: Multiaddr = multiaddr
+                .parse()
+                .map_err(|e| BoxPeerError::InvalidInput(format!("Invalid multiaddr: {}", e)))?;
+            state.client.start_listening(address).await
+        }
+        None => Ok(state.client.local_peer_id().to_string()),
+    }
+}
 
-    let mut client = state.client.lock().await;
+#[tauri::command]
+async fn list_peers(state: State<'_, AppState>) -> Result<Vec<PeerInfo>, BoxPeerError> {
+    state.client.list_peers().await
+}
 
-    let id = client
-        .start_listening(address.clone())
-        .await
-        .map_err(|e| e.to_string())?;
+#[tauri::command]
+async fn peer_transports(state: State<'_, AppState>) -> Result<Vec<PeerTransports>, BoxPeerError> {
+    state.client.peer_transports().await
+}
 
-    let peer_id: PeerId = id.parse().expect("Error parsing");
-    Ok(id)
+#[tauri::command]
+async fn nat_status(state: State<'_, AppState>) -> Result<NatInfo, BoxPeerError> {
+    state.client.nat_info().await
 }
 
 #[tauri::command]
-async fn list_peers(state: State<'_, AppState>) -> Result<Vec<String>, String> {
-    let mut client = state.client.lock().await;
-    match client.get_peers_count().await {
-        Ok(peers) => {
-            let peer_strings: Vec<String> =
-                peers.into_iter().map(|peer| peer.to_string()).collect();
-            Ok(peer_strings)
-        }
-        Err(e) => Err(format!("Failed to get peers: {}", e)),
+async fn relay_stats(state: State<'_, AppState>) -> Result<RelayStats, BoxPeerError> {
+    state.client.relay_stats().await
+}
+
+#[tauri::command]
+async fn bandwidth_stats(state: State<'_, AppState>) -> Result<BandwidthStats, BoxPeerError> {
+    Ok(state.client.bandwidth_stats())
+}
+
+#[tauri::command]
+async fn bandwidth_limits(state: State<'_, AppState>) -> Result<BandwidthLimits, BoxPeerError> {
+    Ok(state.client.bandwidth_limits())
+}
+
+#[tauri::command]
+async fn set_bandwidth_limits(
+    state: State<'_, AppState>,
+    limits: BandwidthLimits,
+) -> Result<(), BoxPeerError> {
+    state.client.set_bandwidth_limits(limits)
+}
+
+#[tauri::command]
+async fn content_announcements(
+    state: State<'_, AppState>,
+) -> Result<Vec<Announcement>, BoxPeerError> {
+    state.client.announcements().await
+}
+
+#[tauri::command]
+async fn connect_peer(
+    state: State<'_, AppState>,
+    multiaddr: String,
+) -> Result<String, BoxPeerError> {
+    let addr: Multiaddr = multiaddr
+        .parse()
+        .map_err(|e| BoxPeerError::InvalidInput(format!("Invalid multiaddr: {}", e)))?;
+    let peer_id = state.client.dial(addr).await?;
+    Ok(peer_id.to_string())
+}
+
+#[tauri::command]
+async fn disconnect_peer(state: State<'_, AppState>, peer_id: String) -> Result<(), BoxPeerError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| BoxPeerError::InvalidInput(format!("Invalid peer ID: {}", e)))?;
+    state.client.disconnect(peer_id).await
+}
+
+#[derive(Serialize, Clone)]
+struct ProviderFound {
+    cid: String,
+    provider: PeerInfo,
+}
+
+/// Emits a `provider-found` event for each provider as the lookup finds it
+/// and returns the full list once it's over.
+#[tauri::command]
+async fn find_providers(
+    window: Window,
+    state: State<'_, AppState>,
+    cid: String,
+) -> Result<Vec<PeerInfo>, BoxPeerError> {
+    let cid = Cid::try_from(cid)?;
+    let mut providers = state.client.find_providers(cid).await?;
+    let mut found = Vec::new();
+    while let Some(provider) = providers.next().await {
+        let _ = window.emit(
+            "provider-found",
+            ProviderFound {
+                cid: cid.to_string(),
+                provider: provider.clone(),
+            },
+        );
+        found.push(provider);
     }
+    Ok(found)
+}
+
+#[tauri::command]
+async fn peer_info(
+    state: State<'_, AppState>,
+    peer_id: String,
+) -> Result<PeerDetails, BoxPeerError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| BoxPeerError::InvalidInput(format!("Invalid peer ID: {}", e)))?;
+    state.client.peer_info(peer_id).await
+}
+
+#[tauri::command]
+async fn ban_peer(state: State<'_, AppState>, peer_id: String) -> Result<(), BoxPeerError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| BoxPeerError::InvalidInput(format!("Invalid peer ID: {}", e)))?;
+    state.client.ban_peer(peer_id).await
+}
+
+#[tauri::command]
+async fn unban_peer(state: State<'_, AppState>, peer_id: String) -> Result<bool, BoxPeerError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| BoxPeerError::InvalidInput(format!("Invalid peer ID: {}", e)))?;
+    state.client.unban_peer(peer_id).await
+}
+
+#[tauri::command]
+async fn list_banned(state: State<'_, AppState>) -> Result<Vec<BannedPeer>, BoxPeerError> {
+    state.client.list_banned().await
+}
+
+#[tauri::command]
+async fn node_type(state: State<'_, AppState>) -> Result<NodeType, BoxPeerError> {
+    state.client.node_type().await
+}
+
+#[tauri::command]
+async fn set_node_type(
+    state: State<'_, AppState>,
+    node_type: NodeType,
+) -> Result<(), BoxPeerError> {
+    state.client.set_node_type(node_type).await
 }
 
 #[tauri::command]
-async fn upload_file(state: State<'_, AppState>, file_path: String) -> Result<String, String> {
+async fn request_replication(
+    state: State<'_, AppState>,
+    peer_id: String,
+    cid: String,
+) -> Result<bool, BoxPeerError> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .map_err(|e| BoxPeerError::InvalidInput(format!("Invalid peer ID: {}", e)))?;
+    let cid = Cid::try_from(cid)?;
+    state.client.request_replication(peer_id, cid).await
+}
+
+#[tauri::command]
+async fn upload_file(
+    state: State<'_, AppState>,
+    file_path: String,
+) -> Result<String, BoxPeerError> {
     let path = PathBuf::from(file_path);
-    let mut client = state.client.lock().await;
-    client
+    state
+        .client
         .upload_file(path)
         .await
         .map(|cid| cid.to_string())
-        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-async fn request_file(state: State<'_, AppState>, cid: String) -> Result<Vec<u8>, String> {
-    let cid = cid
-        .parse()
-        .map_err(|e| format!("Request file error: {}", e))?;
-    let mut client = state.client.lock().await;
-    client.request_file(cid).await.map_err(|e| e.to_string())
+async fn request_file(state: State<'_, AppState>, cid: String) -> Result<Vec<u8>, BoxPeerError> {
+    let cid = Cid::try_from(cid)?;
+    state.client.request_file(cid).await
 }
 
 #[tauri::command]
-async fn lock_file(state: State<'_, AppState>, cid: String) -> Result<String, String> {
-    let cid = cid.parse().map_err(|e| format!("Lock file error: {}", e))?;
-    let mut client = state.client.lock().await;
-    client.lock_file(cid).await.map_err(|e| e.to_string())
+async fn lock_file(state: State<'_, AppState>, cid: String) -> Result<String, BoxPeerError> {
+    let cid = Cid::try_from(cid)?;
+    state.client.lock_file(cid).await
 }
 
 #[tauri::command]
-async fn has_file(state: State<'_, AppState>, cid: String) -> Result<bool, String> {
-    let cid = cid.parse().map_err(|e| format!("Lock file error: {}", e))?;
-    let mut client = state.client.lock().await;
-    client.owned_file(cid).await.map_err(|e| e.to_string())
+async fn has_file(state: State<'_, AppState>, cid: String) -> Result<bool, BoxPeerError> {
+    let cid = Cid::try_from(cid)?;
+    state.client.owned_file(cid).await
 }
 
 #[tauri::command]
 async fn request_files(
     state: State<'_, AppState>,
     cid_strings: Vec<String>,
-) -> Result<Vec<Vec<u8>>, String> {
-    let cids: Result<Vec<Cid>, _> = cid_strings.into_iter().map(|s| Cid::try_from(s)).collect();
-    let cids = cids.map_err(|e| format!("Invalid CID: {}", e))?;
-    let mut client = state.client.lock().await;
-    client.get_all_files(cids).await.map_err(|e| e.to_string())
+) -> Result<Vec<Vec<u8>>, BoxPeerError> {
+    let cids = cid_strings
+        .into_iter()
+        .map(Cid::try_from)
+        .collect::<Result<Vec<Cid>, _>>()?;
+    state.client.get_all_files(cids).await
+}
+
+#[tauri::command]
+async fn export_car(
+    state: State<'_, AppState>,
+    cid: String,
+    path: String,
+) -> Result<u64, BoxPeerError> {
+    let cid = Cid::try_from(cid)?;
+    state.client.export_car(cid, PathBuf::from(path)).await
+}
+
+#[tauri::command]
+async fn import_car(
+    state: State<'_, AppState>,
+    path: String,
+    provide: bool,
+) -> Result<Vec<String>, BoxPeerError> {
+    state
+        .client
+        .import_car(PathBuf::from(path), provide)
+        .await
+        .map(|roots| roots.iter().map(|cid| cid.to_string()).collect())
+}
+
+#[tauri::command]
+async fn storage_health(state: State<'_, AppState>) -> Result<StorageHealth, BoxPeerError> {
+    state.client.storage_health().await
+}
+
+#[tauri::command]
+async fn storage_stats(state: State<'_, AppState>) -> Result<StorageStats, BoxPeerError> {
+    state.client.storage_stats().await
+}
+
+#[tauri::command]
+async fn scrub_storage(state: State<'_, AppState>) -> Result<ScrubReport, BoxPeerError> {
+    state.client.scrub_storage().await
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _ = tracing_subscriber::fmt().with_max_level(Level::WARN).init();
-    let bootstrap_peers: Option<Vec<Multiaddr>> =
-        Some(vec!["/ip4/203.161.57.50/udp/9090/quic-v1".parse().unwrap()]);
+    let config = config::load_config();
     let (client, network_events, network_event_loop) =
-        P2PCDNClient::new(bootstrap_peers, None).await?;
-    spawn(network_event_loop.run());
+        P2PCDNClient::new(Some(config.bootstrap_peers()), None, &config).await?;
     let app_state = AppState {
-        client: Arc::new(AsyncMutex::new(client)),
+        client,
         network_events: Arc::new(AsyncMutex::new(Box::pin(network_events))),
+        shutdown_started: Default::default(),
+        shutdown_done: Default::default(),
     };
 
     tauri::Builder::default()
         .manage(app_state)
+        .setup(|app| {
+            let handle = app.handle();
+            spawn(async move {
+                // Requests fail with `NetworkUnavailable` from here on; the
+                // event lets the UI say so before the user tries one.
+                if let Err(e) = network_event_loop.run().await {
+                    let _ = handle.emit_all("network-unavailable", format!("{:#}", e));
+                }
+            });
+            let handle = app.handle();
+            spawn(async move {
+                wait_for_sigterm().await;
+                begin_shutdown(handle);
+            });
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             start_listening,
             upload_file,
+            node_type,
+            set_node_type,
+            request_replication,
+            content_announcements,
             list_peers,
+            find_providers,
+            peer_info,
+            connect_peer,
+            disconnect_peer,
+            ban_peer,
+            unban_peer,
+            list_banned,
+            peer_transports,
+            nat_status,
+            relay_stats,
+            bandwidth_stats,
+            bandwidth_limits,
+            set_bandwidth_limits,
             request_file,
             request_files,
             lock_file,
-            has_file
+            has_file,
+            export_car,
+            import_car,
+            storage_health,
+            storage_stats,
+            scrub_storage
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running BoxPeer application");
+        .build(tauri::generate_context!())
+        .expect("error while building BoxPeer application")
+        .run(|app, event| {
+            // Closing the last window asks to exit; hold off until the node
+            // has shut down.
+            if let RunEvent::ExitRequested { api, .. } = event {
+                if !app.state::<AppState>().shutdown_done.load(Ordering::SeqCst) {
+                    api.prevent_exit();
+                    begin_shutdown(app.clone());
+                }
+            }
+        });
 
     Ok(())
 }
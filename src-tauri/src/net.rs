@@ -1,33 +1,59 @@
+use crate::announce::{self, Announcement, AnnouncementFeed, ContentManifest};
+use crate::bandwidth::{BandwidthMeter, BandwidthStats, TrafficStats};
+use crate::bitswap::{self, Bitswap};
+use crate::car;
+use crate::config::{
+    update_config, BandwidthLimits, ConnectionLimitsConfig, NodeConfig, RelayServerConfig,
+};
+use crate::error::BoxPeerError;
+use crate::network::{agent_version, Network};
 use crate::node::boxpeer_dir;
-use crate::node::load_or_generate_keypair;
+use crate::node::{
+    load_or_generate_keypair, ConnectionDetails, ConnectionDirection, NodeType, PeerDetails,
+    PeerInfo,
+};
+use crate::scoring::{BannedPeer, PeerScores, AUTO_BAN_DURATION, BAN_SWEEP_INTERVAL};
+use crate::storage::{
+    storage_stats, unix_now, Cache, Pins, ScrubReport, Scrubber, StorageHealth, StorageStats,
+};
+use crate::store::{BitswapStore, BlockCipher, NodeBlockstore};
+use crate::transport::direct_transport;
 use anyhow::{anyhow, Result};
 use beetswap;
 use blockstore::block::CidError;
-use blockstore::{block::Block, Blockstore, SledBlockstore};
+use blockstore::{block::Block, Blockstore};
 use cid::Cid;
 use futures::channel::{mpsc, oneshot};
-use futures::{SinkExt, Stream, StreamExt};
-use libp2p::kad::store::MemoryStore;
+use futures::{FutureExt, SinkExt, Stream, StreamExt};
+use libp2p::core::transport::ListenerId;
+use libp2p::kad::store::{MemoryStore, RecordStore};
+use libp2p::metrics::Registry;
 use libp2p::multiaddr::Protocol;
+use libp2p::PeerId;
 use libp2p::{
-    identify, identity, kad, mdns,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    Multiaddr, Swarm, SwarmBuilder,
+    allow_block_list, autonat, connection_limits, dcutr, gossipsub, identify, identity, kad, mdns,
+    memory_connection_limits, noise, ping, relay, request_response,
+    swarm::{
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, NetworkBehaviour, SwarmEvent,
+    },
+    upnp, yamux, Multiaddr, Swarm, SwarmBuilder,
 };
-use libp2p::{PeerId, StreamProtocol};
 use libp2p_kad::RecordKey;
 use multihash_codetable::{Code, MultihashDigest};
+use serde::{Deserialize, Serialize};
 use sled;
-use std::collections::{HashMap, HashSet};
+use std::collections::{hash_map, HashMap, HashSet};
 use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::select;
-use tracing::{info, warn};
-
-const BOXPEER_PROTO_NAME: StreamProtocol = StreamProtocol::new("/ipfs/0.1.0");
+use tracing::{debug, error, info, warn};
 
 struct FileBlock(Vec<u8>);
 
@@ -42,23 +68,197 @@ impl Block<64> for FileBlock {
     }
 }
 
+#[derive(Serialize, Clone)]
+pub struct PeerTransports {
+    pub peer_id: String,
+    pub transports: Vec<String>,
+}
+
+/// Answers a command. A caller that stopped waiting, e.g. a UI that timed
+/// out, is nothing to stop the event loop for.
+fn reply<T>(sender: oneshot::Sender<T>, value: T, what: &str) {
+    if sender.send(value).is_err() {
+        debug!("Caller stopped waiting for {}", what);
+    }
+}
+
+/// Names the transport a connection runs over from its remote address.
+fn transport_name(addr: &Multiaddr) -> &'static str {
+    let mut name = "unknown";
+    for protocol in addr.iter() {
+        name = match protocol {
+            Protocol::P2pCircuit => return "relay",
+            Protocol::QuicV1 => "quic",
+            Protocol::Ws(_) | Protocol::Wss(_) => "websocket",
+            Protocol::Tcp(_) if name == "unknown" => "tcp",
+            _ => name,
+        };
+    }
+    name
+}
+
+/// Whether `addr` could be reached from the internet at large. Private,
+/// loopback and link-local addresses only mean something on the network
+/// they came from, so they're kept out of the DHT; mDNS finds LAN peers.
+fn is_global(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 is carrier-grade NAT.
+            let shared = a == 100 && (64..128).contains(&b);
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || shared)
+        }
+        Some(Protocol::Ip6(ip)) => {
+            let first = ip.segments()[0];
+            // fc00::/7 is unique local, fe80::/10 link-local.
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+        Some(Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host)) => {
+            host != "localhost"
+        }
+        _ => true,
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct NatInfo {
+    pub status: String,
+    pub public_address: Option<String>,
+    pub relay_addresses: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct RelayStats {
+    pub enabled: bool,
+    pub active_reservations: usize,
+    pub reservations_accepted: u64,
+    pub reservations_denied: u64,
+    pub active_circuits: u64,
+    pub circuits_accepted: u64,
+    pub circuits_denied: u64,
+    pub circuits_closed: u64,
+    // Lifetime bytes carried over circuits through us.
+    pub bytes_relayed: TrafficStats,
+}
+
+fn connection_limits_config(
+    config: &ConnectionLimitsConfig,
+) -> connection_limits::ConnectionLimits {
+    connection_limits::ConnectionLimits::default()
+        .with_max_established(config.max_established)
+        .with_max_established_per_peer(config.max_established_per_peer)
+        .with_max_pending_incoming(config.max_pending_incoming)
+        .with_max_pending_outgoing(config.max_pending_outgoing)
+}
+
+fn relay_server_config(config: &RelayServerConfig) -> relay::Config {
+    relay::Config {
+        max_reservations: config.max_reservations,
+        max_reservations_per_peer: config.max_reservations_per_peer,
+        reservation_duration: Duration::from_secs(config.reservation_duration_secs),
+        max_circuits: config.max_circuits,
+        max_circuits_per_peer: config.max_circuits_per_peer,
+        max_circuit_duration: Duration::from_secs(config.max_circuit_duration_secs),
+        max_circuit_bytes: config.max_circuit_bytes,
+        ..Default::default()
+    }
+}
+
+/// Private networks can't use QUIC or reach the public bootstrap node, so
+/// they need bootstrap peers of their own on TCP or WebSocket.
+fn check_private_bootstrap(peers: &[Multiaddr]) -> std::result::Result<(), String> {
+    if peers.is_empty() {
+        return Err(
+            "A private network needs bootstrap peers of its own under `bootstrap` in config.json"
+                .into(),
+        );
+    }
+    let quic = peers.iter().find(|address| {
+        address
+            .iter()
+            .any(|protocol| matches!(protocol, Protocol::Quic | Protocol::QuicV1))
+    });
+    match quic {
+        Some(address) => Err(format!(
+            "Bootstrap peer {} uses QUIC, which private networks can't; give its TCP or WebSocket address instead",
+            address
+        )),
+        None => Ok(()),
+    }
+}
+
 #[derive(NetworkBehaviour)]
 struct Behaviour {
+    // Bans and limits come first so connections are denied before other
+    // behaviours see them.
+    blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    limits: connection_limits::Behaviour,
+    memory_limits: Toggle<memory_connection_limits::Behaviour>,
     identify: identify::Behaviour,
-    bitswap: beetswap::Behaviour<64, SledBlockstore>,
+    ping: ping::Behaviour,
+    bitswap: Bitswap,
     mdns: mdns::tokio::Behaviour,
     kademlia: kad::Behaviour<MemoryStore>,
+    gossipsub: gossipsub::Behaviour,
+    // Each side sends its own role and gets the other's back.
+    role: request_response::json::Behaviour<NodeType, NodeType>,
+    replicate: request_response::json::Behaviour<ReplicationRequest, ReplicationResponse>,
+    relay_client: relay::client::Behaviour,
+    autonat: autonat::Behaviour,
+    dcutr: dcutr::Behaviour,
+    relay_server: Toggle<relay::Behaviour>,
+    upnp: Toggle<upnp::tokio::Behaviour>,
+}
+
+// Replicas a distributor fetches at once, and how long each may take.
+const MAX_REPLICATIONS: usize = 16;
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const REPLICATION_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+// Room kept for a replica when the requester didn't say how big it is.
+const UNKNOWN_REPLICA_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicationRequest {
+    cid: String,
+    // Block size, so the distributor can check it has room before agreeing.
+    #[serde(default)]
+    size: Option<u64>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ReplicationResponse {
+    Accepted,
+    // Only distributors take on replicas.
+    Declined,
+}
+
+/// Handle to the node. Clones share the same event loop and storage, so
+/// callers can each hold one and issue requests concurrently.
+#[derive(Clone)]
 pub struct P2PCDNClient {
-    blockstore: Arc<SledBlockstore>,
+    blockstore: Arc<NodeBlockstore>,
     command_sender: mpsc::Sender<Command>,
+    scrubber: Scrubber,
+    pins: Pins,
+    cache: Cache,
+    bandwidth: BandwidthMeter,
+    keypair: identity::Keypair,
 }
 
 impl P2PCDNClient {
     pub async fn new(
         bootstrap_peers: Option<Vec<Multiaddr>>,
         secret_key_seed: Option<u8>,
+        config: &NodeConfig,
     ) -> std::result::Result<
         (P2PCDNClient, impl Stream<Item = kad::Event>, EventLoop),
         Box<dyn Error>,
@@ -69,7 +269,7 @@ impl P2PCDNClient {
                 bytes[0] = seed;
                 identity::Keypair::ed25519_from_bytes(bytes)?
             }
-            None => load_or_generate_keypair(),
+            None => load_or_generate_keypair(config.encrypt_at_rest)?,
         };
 
         let peer_id = id_keys.public().to_peer_id();
@@ -94,48 +294,157 @@ impl P2PCDNClient {
             }
         }
 
-        let identify = identify::Behaviour::new(identify::Config::new(
-            BOXPEER_PROTO_NAME.to_string(),
-            id_keys.public().clone(),
-        ));
+        let network = Network::load(config)?;
+        if let Some(psk) = network.psk() {
+            info!("Joining private network {}", psk.fingerprint());
+            check_private_bootstrap(bootstrap_peers.as_deref().unwrap_or_default())?;
+        }
+
+        let identify = identify::Behaviour::new(
+            identify::Config::new(network.protocol_version(), id_keys.public().clone())
+                .with_agent_version(agent_version()),
+        );
 
-        let blockstore = Arc::new(SledBlockstore::new(db).await.expect("Err"));
-        let mut cfg = kad::Config::new(BOXPEER_PROTO_NAME);
+        let cipher = if config.encrypt_at_rest {
+            Some(
+                BlockCipher::from_keypair(&id_keys)
+                    .ok_or("Keypair can't derive an encryption key")?,
+            )
+        } else {
+            None
+        };
+        let blockstore = Arc::new(
+            NodeBlockstore::open(
+                &config.blockstore,
+                &db,
+                PathBuf::from(&path).as_path(),
+                cipher,
+                config.storage_quota_bytes,
+            )
+            .await?,
+        );
+        let kad_protocols = network.kad_protocols();
+        let mut cfg = kad::Config::new(kad_protocols[0].clone());
+        // Still the only way to offer older protocol versions alongside the
+        // current one during negotiation.
+        #[allow(deprecated)]
+        cfg.set_protocol_names(kad_protocols);
 
         cfg.set_periodic_bootstrap_interval(Some(Duration::from_secs(60)));
         cfg.set_record_ttl(None);
 
+        let meter = BandwidthMeter::open(&db, config.bandwidth_limits)?;
+        let mut registry = Registry::default();
+
+        let serve_blocks = Arc::new(AtomicBool::new(false));
+        let bitswap_store = Arc::new(BitswapStore::new(blockstore.clone(), serve_blocks.clone()));
+        let keypair = id_keys.clone();
+        let behaviour = |key: &identity::Keypair, relay_client| Behaviour {
+            blocked: Default::default(),
+            limits: connection_limits::Behaviour::new(connection_limits_config(
+                &config.connection_limits,
+            )),
+            memory_limits: config
+                .connection_limits
+                .max_memory_fraction
+                .map(memory_connection_limits::Behaviour::with_max_percentage)
+                .into(),
+            kademlia: kad::Behaviour::with_config(
+                peer_id,
+                MemoryStore::new(key.public().to_peer_id()),
+                cfg,
+            ),
+            mdns: mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())
+                .expect("Error with mdns configuring"),
+            gossipsub: gossipsub::Behaviour::new(
+                gossipsub::MessageAuthenticity::Signed(key.clone()),
+                announce::gossipsub_config(),
+            )
+            .expect("Error with gossipsub configuring"),
+            role: request_response::json::Behaviour::new(
+                [(
+                    network.role_protocol(),
+                    request_response::ProtocolSupport::Full,
+                )],
+                request_response::Config::default(),
+            ),
+            replicate: request_response::json::Behaviour::new(
+                [(
+                    network.replicate_protocol(),
+                    request_response::ProtocolSupport::Full,
+                )],
+                request_response::Config::default(),
+            ),
+            bitswap: Bitswap::new(bitswap_store, meter.dont_haves()),
+            identify,
+            ping: ping::Behaviour::default(),
+            relay_client,
+            autonat: autonat::Behaviour::new(key.public().to_peer_id(), autonat::Config::default()),
+            dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
+            relay_server: config
+                .relay_server
+                .enabled
+                .then(|| {
+                    relay::Behaviour::new(
+                        key.public().to_peer_id(),
+                        relay_server_config(&config.relay_server),
+                    )
+                })
+                .into(),
+            upnp: config
+                .transports
+                .upnp
+                .then(upnp::tokio::Behaviour::default)
+                .into(),
+        };
+        let swarm_config = |cfg: libp2p::swarm::Config| {
+            cfg.with_idle_connection_timeout(Duration::from_secs(
+                config.connection_limits.idle_connection_timeout_secs,
+            ))
+        };
+
+        // Relayed connections only get noise, but the hop to the relay is
+        // itself protected on a private network. They aren't metered stream
+        // by stream; their traffic shows up under the relay protocol on the
+        // connection to the relay.
         let mut swarm = SwarmBuilder::with_existing_identity(id_keys)
             .with_tokio()
-            .with_quic()
-            .with_behaviour(|key| Behaviour {
-                kademlia: kad::Behaviour::with_config(
-                    peer_id,
-                    MemoryStore::new(key.public().to_peer_id()),
-                    cfg,
-                ),
-                mdns: mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    key.public().to_peer_id(),
-                )
-                .expect("Error with mdns configuring"),
-                bitswap: beetswap::Behaviour::new(blockstore.clone()),
-                identify,
-            })?
-            .with_swarm_config(|cfg| {
-                cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX))
-            })
+            .with_other_transport(|key| direct_transport(key, network.psk(), meter.clone()))?
+            .with_dns()?
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_bandwidth_metrics(&mut registry)
+            .with_behaviour(behaviour)?
+            .with_swarm_config(swarm_config)
             .build();
+        meter.attach(registry);
+        meter.spawn_sampler();
 
         swarm
             .behaviour_mut()
-            .kademlia
-            .set_mode(Some(kad::Mode::Server));
+            .gossipsub
+            .subscribe(&network.announce_topic())?;
 
-        let address: Multiaddr = "/ip4/0.0.0.0/udp/0/quic-v1"
-            .parse()
-            .expect("Error with address");
-        swarm.listen_on(address).expect("Error listening");
+        // QUIC can't carry the pnet handshake, so private networks skip it.
+        // If the configured port is taken we still come up, just on a port
+        // picked by the OS.
+        for address in config.transports.listen_addresses(network.psk().is_none()) {
+            if let Err(e) = swarm.listen_on(address.clone()) {
+                warn!("Failed to listen on {}: {:?}", address, e);
+                let fallback: Multiaddr = address
+                    .iter()
+                    .map(|protocol| match protocol {
+                        Protocol::Tcp(_) => Protocol::Tcp(0),
+                        Protocol::Udp(_) => Protocol::Udp(0),
+                        protocol => protocol,
+                    })
+                    .collect();
+                if fallback != address {
+                    if let Err(e) = swarm.listen_on(fallback.clone()) {
+                        warn!("Failed to listen on {}: {:?}", fallback, e);
+                    }
+                }
+            }
+        }
 
         // Dial bootstrap peers if provided
         if let Some(peers) = bootstrap_peers {
@@ -148,81 +457,239 @@ impl P2PCDNClient {
             }
         }
 
-        let (command_sender, command_receiver) = mpsc::channel(0);
+        // Enough room for a burst of UI calls without the event loop having
+        // to keep up with each one before the next is queued.
+        let (command_sender, command_receiver) = mpsc::channel(32);
         let (event_sender, event_receiver) = mpsc::channel(0);
+        let pins = Pins::open(&db)?;
+        let cache = Cache::open(&db, blockstore.clone(), pins.clone())?;
+        let scores = PeerScores::open(&db)?;
+        let scrubber = Scrubber::new(blockstore.clone(), db.clone(), command_sender.clone());
+        scrubber.spawn_periodic();
         Ok((
             P2PCDNClient {
                 blockstore: blockstore.clone(),
                 command_sender,
+                scrubber,
+                pins,
+                cache: cache.clone(),
+                bandwidth: meter,
+                keypair,
             },
             event_receiver,
-            EventLoop::new(swarm, command_receiver, event_sender, blockstore),
+            EventLoop::new(
+                swarm,
+                command_receiver,
+                event_sender,
+                blockstore,
+                config.relays.clone(),
+                network,
+                config.node_type,
+                config.serve_blocks,
+                serve_blocks,
+                cache,
+                scores,
+                db,
+            ),
         ))
     }
 
-    pub(crate) async fn get_peers_count(
-        &mut self,
-    ) -> std::result::Result<Vec<PeerId>, Box<dyn Error + Send>> {
+    pub(crate) async fn list_peers(&self) -> Result<Vec<PeerInfo>, BoxPeerError> {
         let (sender, receiver) = oneshot::channel();
         self.command_sender
+            .clone()
             .send(Command::GetPeers { sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    /// Looks up who provides `cid`. Providers arrive on the returned stream
+    /// as they're found, once we know their role or failed to ask for it, and
+    /// the stream ends when the lookup is over.
+    pub(crate) async fn find_providers(
+        &self,
+        cid: Cid,
+    ) -> Result<mpsc::UnboundedReceiver<PeerInfo>, BoxPeerError> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.command_sender
+            .clone()
+            .send(Command::GetProviders {
+                cid: RecordKey::new(&cid.to_bytes()),
+                sender,
+            })
+            .await?;
+        Ok(receiver)
     }
 
-    pub(crate) async fn start_listening(&mut self, addr: Multiaddr) -> Result<String> {
+    pub(crate) async fn peer_info(&self, peer_id: PeerId) -> Result<PeerDetails, BoxPeerError> {
         let (sender, receiver) = oneshot::channel();
         self.command_sender
+            .clone()
+            .send(Command::GetPeerInfo { peer_id, sender })
+            .await?;
+        receiver
+            .await?
+            .ok_or_else(|| BoxPeerError::NotConnected(peer_id.to_string()))
+    }
+
+    pub(crate) async fn peer_transports(&self) -> Result<Vec<PeerTransports>, BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::GetPeerTransports { sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    pub(crate) async fn nat_info(&self) -> Result<NatInfo, BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::GetNatInfo { sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    pub(crate) async fn relay_stats(&self) -> Result<RelayStats, BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::GetRelayStats { sender })
+            .await?;
+        Ok(RelayStats {
+            bytes_relayed: self.bandwidth.relayed(),
+            ..receiver.await?
+        })
+    }
+
+    pub(crate) fn bandwidth_stats(&self) -> BandwidthStats {
+        self.bandwidth.stats()
+    }
+
+    pub(crate) fn bandwidth_limits(&self) -> BandwidthLimits {
+        self.bandwidth.limits()
+    }
+
+    /// Applies new rate limits to queued and future blocks straight away and
+    /// saves them so they survive a restart.
+    pub(crate) fn set_bandwidth_limits(&self, limits: BandwidthLimits) -> Result<(), BoxPeerError> {
+        self.bandwidth.set_limits(limits);
+        update_config(|config| config.bandwidth_limits = limits)?;
+        Ok(())
+    }
+
+    /// Stops the node: pending requests fail, connections and listeners are
+    /// closed and everything is flushed to disk. Resolves once the event loop
+    /// has exited.
+    pub(crate) async fn shutdown(&self) -> Result<(), BoxPeerError> {
+        if let Err(e) = self.bandwidth.persist() {
+            warn!("Failed to save bandwidth totals: {:?}", e);
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::Shutdown { sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    pub(crate) fn local_peer_id(&self) -> PeerId {
+        self.keypair.public().to_peer_id()
+    }
+
+    pub(crate) async fn start_listening(&self, addr: Multiaddr) -> Result<String, BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
             .send(Command::StartListening { addr, sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .await?;
+        Ok(receiver.await??)
     }
 
-    pub async fn upload_file(&mut self, file_path: PathBuf) -> Result<String> {
+    pub async fn upload_file(&self, file_path: PathBuf) -> Result<String, BoxPeerError> {
+        let size = fs::metadata(&file_path)
+            .map_err(|e| BoxPeerError::InvalidInput(format!("Can't read {:?}: {}", file_path, e)))?
+            .len();
+        self.ensure_space(size).await?;
+        let name = file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
         let (sender, receiver) = oneshot::channel();
         self.command_sender
+            .clone()
             .send(Command::UploadFile { file_path, sender })
             .await?;
 
         let cid = receiver.await??;
+        // Providers publish content and keep it for good. On other roles an
+        // upload is cached like fetched blocks and may be evicted for space.
+        if self.node_type().await? == NodeType::Provider {
+            self.pins.pin(&self.blockstore, cid).await?;
+        } else {
+            self.cache.touch(&cid, size)?;
+        }
+
+        let manifest = ContentManifest {
+            cid: cid.to_string(),
+            name,
+            size,
+            published_at: unix_now(),
+        };
+        // The upload itself succeeded, so a failed announcement is only logged.
+        if let Err(e) = self.announce(manifest).await {
+            warn!("Failed to announce {}: {:?}", cid, e);
+        }
         Ok(cid.to_string())
     }
-    pub async fn get_all_files(&mut self, cids: Vec<Cid>) -> Result<Vec<Vec<u8>>> {
+
+    /// Signs `manifest` with our key and publishes it on the announcement topic.
+    pub(crate) async fn announce(&self, manifest: ContentManifest) -> Result<(), BoxPeerError> {
+        let data = announce::sign(&self.keypair, &manifest)?;
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::Announce {
+                manifest,
+                data,
+                sender,
+            })
+            .await?;
+        Ok(receiver.await??)
+    }
+
+    pub(crate) async fn announcements(&self) -> Result<Vec<Announcement>, BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::GetAnnouncements { sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+    pub async fn get_all_files(&self, cids: Vec<Cid>) -> Result<Vec<Vec<u8>>, BoxPeerError> {
         let mut contents = Vec::new();
         for cid in cids {
-            let content = self.request_file(cid).await.expect("An error occurred");
-            contents.push(content);
+            contents.push(self.request_file(cid).await?);
         }
         Ok(contents)
     }
 
-    pub async fn owned_file(&mut self, cid: Cid) -> Result<bool> {
-        if self
-            .blockstore
-            .has(&cid)
-            .await
-            .expect("Error with blockstore")
-        {
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    pub async fn owned_file(&self, cid: Cid) -> Result<bool, BoxPeerError> {
+        Ok(self.blockstore.has(&cid).await?)
     }
 
-    pub async fn request_file(&mut self, cid: Cid) -> Result<Vec<u8>> {
-        if !self
-            .blockstore
-            .has(&cid)
-            .await
-            .expect("Error with blockstore")
-        {
-            info!("CID {:?} not found in local blockstore.", cid);
+    pub async fn request_file(&self, cid: Cid) -> Result<Vec<u8>, BoxPeerError> {
+        // Bitswap may not be allowed to see our own blocks, see `BitswapStore`.
+        match self.blockstore.get(&cid).await {
+            Ok(Some(data)) => return Ok(data),
+            Ok(None) => info!("CID {:?} not found in local blockstore.", cid),
+            Err(e) => warn!("Failed to read {:?} from local blockstore: {:?}", cid, e),
         }
 
         let (sender, receiver) = oneshot::channel();
         self.command_sender
+            .clone()
             .send(Command::RequestFile { cid, sender })
             .await?;
 
@@ -230,7 +697,7 @@ impl P2PCDNClient {
         Ok(file_data)
     }
 
-    pub async fn lock_file(&mut self, cid: Cid) -> Result<String, anyhow::Error> {
+    pub async fn lock_file(&self, cid: Cid) -> Result<String, BoxPeerError> {
         // Check if the file exists in the local blockstore
         if let Ok(true) = self.blockstore.has(&cid).await {
             // File already exists locally, retrieve it
@@ -242,19 +709,183 @@ impl P2PCDNClient {
         // File not found in local blockstore, request it from peers
         let (sender, receiver) = oneshot::channel();
         self.command_sender
+            .clone()
             .send(Command::RequestFile { cid, sender })
-            .await
-            .map_err(|e| anyhow!("Failed to send request for file: {:?}", e))?;
+            .await?;
         let file_data = receiver.await??;
 
-        // Store the retrieved file in the local blockstore
-        self.blockstore
-            .put_keyed(&cid, &file_data)
-            .await
-            .map_err(|e| anyhow!("Failed to store block in blockstore: {:?}", e))?;
+        // Store the retrieved file in the local blockstore, unless bitswap
+        // already did so while fetching it
+        if !self.blockstore.has(&cid).await? {
+            self.ensure_space(file_data.len() as u64).await?;
+            self.blockstore
+                .put_keyed(&cid, &file_data)
+                .await
+                .map_err(|e| self.blockstore.put_error(e, file_data.len() as u64))?;
+        }
+        self.pins.pin(&self.blockstore, cid).await?;
 
         Ok(format!("You are now providing file {:?}", &cid))
     }
+
+    pub async fn export_car(&self, cid: Cid, path: PathBuf) -> Result<u64, BoxPeerError> {
+        Ok(car::export_car(&self.blockstore, cid, &path).await?)
+    }
+
+    pub async fn import_car(&self, path: PathBuf, provide: bool) -> Result<Vec<Cid>, BoxPeerError> {
+        self.ensure_space(fs::metadata(&path)?.len()).await?;
+        let roots = car::import_car(&self.blockstore, &path).await?;
+        for root in &roots {
+            self.pins.pin(&self.blockstore, *root).await?;
+        }
+        if provide {
+            for root in &roots {
+                let (sender, receiver) = oneshot::channel();
+                self.command_sender
+                    .clone()
+                    .send(Command::StartProviding { cid: *root, sender })
+                    .await?;
+                receiver.await??;
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Connects to the peer at `addr`, which must end in `/p2p/<peer id>`,
+    /// and resolves once the connection is up or the dial has failed.
+    pub(crate) async fn dial(&self, addr: Multiaddr) -> Result<PeerId, BoxPeerError> {
+        let Some(Protocol::P2p(peer_id)) = addr.iter().last() else {
+            return Err(BoxPeerError::InvalidInput(format!(
+                "Address {} does not end in a peer ID",
+                addr
+            )));
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::Dial {
+                peer_id,
+                addr,
+                sender,
+            })
+            .await?;
+        receiver.await??;
+        Ok(peer_id)
+    }
+
+    /// Closes every connection to `peer_id` and resolves once they're gone.
+    pub(crate) async fn disconnect(&self, peer_id: PeerId) -> Result<(), BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::Disconnect { peer_id, sender })
+            .await?;
+        Ok(receiver.await??)
+    }
+
+    pub(crate) async fn ban_peer(&self, peer: PeerId) -> Result<(), BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::BanPeer { peer, sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    pub(crate) async fn unban_peer(&self, peer: PeerId) -> Result<bool, BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::UnbanPeer { peer, sender })
+            .await?;
+        Ok(receiver.await??)
+    }
+
+    pub(crate) async fn list_banned(&self) -> Result<Vec<BannedPeer>, BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::ListBanned { sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    pub(crate) async fn node_type(&self) -> Result<NodeType, BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::GetNodeType { sender })
+            .await?;
+        Ok(receiver.await?)
+    }
+
+    /// Switches role without a restart and saves it for the next launch.
+    pub(crate) async fn set_node_type(&self, node_type: NodeType) -> Result<(), BoxPeerError> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::SetNodeType { node_type, sender })
+            .await?;
+        receiver.await?;
+        update_config(|config| config.node_type = node_type)?;
+        Ok(())
+    }
+
+    /// Asks a distributor to fetch and provide `cid`. Returns whether it agreed.
+    pub(crate) async fn request_replication(
+        &self,
+        peer: PeerId,
+        cid: Cid,
+    ) -> Result<bool, BoxPeerError> {
+        let size = self
+            .blockstore
+            .get(&cid)
+            .await?
+            .map(|data| data.len() as u64);
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::RequestReplication {
+                peer,
+                cid,
+                size,
+                sender,
+            })
+            .await?;
+        Ok(receiver.await??)
+    }
+
+    pub async fn scrub_storage(&self) -> Result<ScrubReport, BoxPeerError> {
+        Ok(self.scrubber.scrub().await?)
+    }
+
+    pub async fn storage_health(&self) -> Result<StorageHealth, BoxPeerError> {
+        Ok(self.scrubber.health().await?)
+    }
+
+    pub async fn storage_stats(&self) -> Result<StorageStats, BoxPeerError> {
+        Ok(storage_stats(&self.blockstore, &self.pins)?)
+    }
+
+    /// Makes room for `incoming` more bytes under the quota, evicting cached
+    /// blocks if need be.
+    async fn ensure_space(&self, incoming: u64) -> Result<(), BoxPeerError> {
+        let Some(evicted) = self.cache.make_room(incoming).await? else {
+            return Err(self.blockstore.storage_full(incoming));
+        };
+        if !evicted.is_empty() {
+            let (sender, receiver) = oneshot::channel();
+            self.command_sender
+                .clone()
+                .send(Command::StopProviding {
+                    cids: evicted,
+                    sender,
+                })
+                .await?;
+            receiver.await?;
+        }
+        Ok(())
+    }
 }
 pub enum Command {
     StartListening {
@@ -269,132 +900,1127 @@ pub enum Command {
         cid: Cid,
         sender: oneshot::Sender<Result<Vec<u8>>>,
     },
+    StopProviding {
+        cids: Vec<Cid>,
+        sender: oneshot::Sender<()>,
+    },
+    StartProviding {
+        cid: Cid,
+        sender: oneshot::Sender<Result<()>>,
+    },
     GetProviders {
         cid: RecordKey,
-        sender: oneshot::Sender<HashSet<PeerId>>,
+        sender: mpsc::UnboundedSender<PeerInfo>,
     },
     GetPeers {
-        sender: oneshot::Sender<std::result::Result<Vec<PeerId>, Box<dyn Error + Send>>>,
+        sender: oneshot::Sender<Vec<PeerInfo>>,
+    },
+    GetPeerInfo {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Option<PeerDetails>>,
+    },
+    GetPeerTransports {
+        sender: oneshot::Sender<Vec<PeerTransports>>,
+    },
+    GetNatInfo {
+        sender: oneshot::Sender<NatInfo>,
+    },
+    GetRelayStats {
+        sender: oneshot::Sender<RelayStats>,
+    },
+    Announce {
+        manifest: ContentManifest,
+        data: Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    },
+    GetAnnouncements {
+        sender: oneshot::Sender<Vec<Announcement>>,
+    },
+    GetNodeType {
+        sender: oneshot::Sender<NodeType>,
+    },
+    Dial {
+        peer_id: PeerId,
+        addr: Multiaddr,
+        sender: oneshot::Sender<Result<()>>,
     },
+    Disconnect {
+        peer_id: PeerId,
+        sender: oneshot::Sender<Result<()>>,
+    },
+    Shutdown {
+        sender: oneshot::Sender<()>,
+    },
+    BanPeer {
+        peer: PeerId,
+        sender: oneshot::Sender<()>,
+    },
+    UnbanPeer {
+        peer: PeerId,
+        sender: oneshot::Sender<Result<bool>>,
+    },
+    ListBanned {
+        sender: oneshot::Sender<Vec<BannedPeer>>,
+    },
+    SetNodeType {
+        node_type: NodeType,
+        sender: oneshot::Sender<()>,
+    },
+    RequestReplication {
+        peer: PeerId,
+        cid: Cid,
+        size: Option<u64>,
+        sender: oneshot::Sender<Result<bool>>,
+    },
+}
+
+/// What we've learnt about a connected peer.
+#[derive(Default)]
+struct KnownPeer {
+    listen_addrs: Vec<Multiaddr>,
+    node_type: Option<NodeType>,
+    identity: Option<PeerIdentity>,
+    rtt: Option<Duration>,
+}
+
+/// What a peer told us about itself over identify.
+struct PeerIdentity {
+    agent_version: String,
+    protocol_version: String,
+    protocols: Vec<String>,
+    observed_addr: Multiaddr,
+}
+
+/// A `find_providers` lookup. It stays open until the DHT query is done and
+/// every provider's role request has been answered or has failed.
+struct ProviderSearch {
+    sender: mpsc::UnboundedSender<PeerInfo>,
+    seen: HashSet<PeerId>,
+    pending_roles: usize,
+    finished: bool,
+}
+
+struct OpenConnection {
+    address: Multiaddr,
+    direction: ConnectionDirection,
+    since: Instant,
+}
+
+/// A dial the operator asked for, and everyone waiting on it.
+struct PendingDial {
+    connection_id: ConnectionId,
+    senders: Vec<oneshot::Sender<Result<()>>>,
 }
 
 pub struct EventLoop {
     swarm: Swarm<Behaviour>,
     command_receiver: mpsc::Receiver<Command>,
     event_sender: mpsc::Sender<kad::Event>,
-    pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), Box<dyn Error + Send>>>>,
+    pending_dial: HashMap<PeerId, PendingDial>,
+    pending_disconnects: HashMap<PeerId, Vec<oneshot::Sender<Result<()>>>>,
     queries: HashMap<beetswap::QueryId, Cid>,
     kad_queries: HashMap<libp2p_kad::QueryId, Cid>,
+    // Fetch lookups that have turned up at least one provider.
+    kad_queries_with_providers: HashSet<libp2p_kad::QueryId>,
     pending_requests: HashMap<beetswap::QueryId, oneshot::Sender<Result<Vec<u8>>>>,
-    pending_get_providers: HashMap<kad::QueryId, oneshot::Sender<HashSet<PeerId>>>,
-    blockstore: Arc<SledBlockstore>,
+    provider_searches: HashMap<kad::QueryId, ProviderSearch>,
+    provider_role_requests: HashMap<request_response::OutboundRequestId, kad::QueryId>,
+    blockstore: Arc<NodeBlockstore>,
+    connections: HashMap<PeerId, HashMap<ConnectionId, OpenConnection>>,
+    relays: Vec<Multiaddr>,
+    listeners: HashSet<ListenerId>,
+    relay_listeners: Vec<ListenerId>,
+    relay_addresses: HashSet<Multiaddr>,
+    relay_stats: RelayStats,
+    relay_reservations: HashSet<PeerId>,
+    network: Network,
+    announcements: AnnouncementFeed,
+    node_type: NodeType,
+    peers: HashMap<PeerId, KnownPeer>,
+    serve_blocks_override: Option<bool>,
+    serve_blocks: Arc<AtomicBool>,
+    pending_replications:
+        HashMap<request_response::OutboundRequestId, oneshot::Sender<Result<bool>>>,
+    // Replicas being fetched, with the provider that asked and when.
+    replications: HashMap<beetswap::QueryId, (PeerId, Instant)>,
+    cache: Cache,
+    scores: PeerScores,
+    shutting_down: bool,
+    db: sled::Db,
 }
 impl EventLoop {
     pub(crate) fn new(
         swarm: Swarm<Behaviour>,
         command_receiver: mpsc::Receiver<Command>,
         event_sender: mpsc::Sender<kad::Event>,
-        blockstore: Arc<SledBlockstore>,
+        blockstore: Arc<NodeBlockstore>,
+        relays: Vec<Multiaddr>,
+        network: Network,
+        node_type: NodeType,
+        serve_blocks_override: Option<bool>,
+        serve_blocks: Arc<AtomicBool>,
+        cache: Cache,
+        scores: PeerScores,
+        db: sled::Db,
     ) -> Self {
-        Self {
+        let mut event_loop = Self {
             swarm,
             command_receiver,
             event_sender,
             pending_dial: Default::default(),
+            pending_disconnects: Default::default(),
             queries: Default::default(),
             kad_queries: Default::default(),
+            kad_queries_with_providers: Default::default(),
             pending_requests: Default::default(),
-            pending_get_providers: Default::default(),
+            provider_searches: Default::default(),
+            provider_role_requests: Default::default(),
             blockstore,
+            connections: Default::default(),
+            relays,
+            listeners: Default::default(),
+            relay_listeners: Default::default(),
+            relay_addresses: Default::default(),
+            relay_stats: Default::default(),
+            relay_reservations: Default::default(),
+            network,
+            announcements: Default::default(),
+            node_type,
+            peers: Default::default(),
+            serve_blocks_override,
+            serve_blocks,
+            pending_replications: Default::default(),
+            replications: Default::default(),
+            cache,
+            scores,
+            shutting_down: false,
+            db,
+        };
+        // Bans carried over from last session.
+        let banned: Vec<PeerId> = event_loop.scores.banned_peers().copied().collect();
+        for peer in banned {
+            event_loop.swarm.behaviour_mut().blocked.block_peer(peer);
         }
+        event_loop.apply_role();
+        event_loop
     }
 
-    async fn handle_event(
-        &mut self,
-        event: SwarmEvent<BehaviourEvent>,
-    ) -> Result<(), anyhow::Error> {
-        match event {
-            SwarmEvent::Behaviour(BehaviourEvent::Bitswap(bitswap)) => match bitswap {
-                beetswap::Event::GetQueryResponse { query_id, data } => {
-                    self.queries.get(&query_id);
-                    if let Some(sender) = self.pending_requests.remove(&query_id) {
-                        sender
-                            .send(Ok(data))
-                            .map_err(|e| anyhow!("Failed to send file data: {:?}", e))?;
-                    }
-                }
-                beetswap::Event::GetQueryError { query_id, error } => {
-                    if let Some(sender) = self.pending_requests.remove(&query_id) {
-                        sender
-                            .send(Err(anyhow!("Error for CID {:?}: {:?}", query_id, error)))
-                            .map_err(|e| anyhow!("Failed to send error: {:?}", e))?;
-                    }
-                }
-            },
-            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns_event)) => {
-                if let mdns::Event::Discovered(peers) = mdns_event {
-                    for (peer_id, multiaddr) in peers {
-                        self.swarm
-                            .behaviour_mut()
-                            .kademlia
-                            .add_address(&peer_id, multiaddr.clone());
-                        info!("Discovered Peer MDNS: {:?}", &multiaddr);
-                    }
-                }
-            }
-            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad_event)) => match kad_event {
-                kad::Event::RoutingUpdated {
-                    peer, addresses, ..
-                } => {
-                    if let address = addresses.first() {
-                        info!("Discovered peer via Kademlia: {:?} at {:?}", peer, address);
-                        match self.swarm.dial(address.clone()) {
-                            Ok(()) => {
-                                info!("Dialing peer: {:?}\n", peer);
+    /// Sets the DHT mode and block serving for our role. Consumers stay DHT
+    /// clients; distributors act as servers unless AutoNAT finds them
+    /// unreachable; providers follow AutoNAT, letting Kademlia decide from
+    /// confirmed external addresses until it has an answer.
+    fn apply_role(&mut self) {
+        let nat_status = self.swarm.behaviour().autonat.nat_status();
+        let mode = match (self.node_type, nat_status) {
+            (NodeType::Consumer, _) => Some(kad::Mode::Client),
+            (_, autonat::NatStatus::Private) => Some(kad::Mode::Client),
+            (_, autonat::NatStatus::Public(_)) => Some(kad::Mode::Server),
+            (NodeType::Distributor, autonat::NatStatus::Unknown) => Some(kad::Mode::Server),
+            (NodeType::Provider, autonat::NatStatus::Unknown) => None,
+        };
+        self.swarm.behaviour_mut().kademlia.set_mode(mode);
+
+        let serve = self
+            .serve_blocks_override
+            .unwrap_or(self.node_type != NodeType::Consumer);
+        self.serve_blocks.store(serve, Ordering::Relaxed);
+    }
+
+    fn ban_peer(&mut self, peer: PeerId, reason: String, duration: Option<Duration>) {
+        warn!("Banning {:?}: {}", peer, reason);
+        if let Err(e) = self.scores.ban(peer, reason, duration) {
+            warn!("Failed to save ban on {:?}: {:?}", peer, e);
+        }
+        // The ban wipes its score, so it starts over once the ban lifts.
+        self.swarm.behaviour_mut().bitswap.unshun(&peer);
+        // Also closes any open connections to the peer.
+        self.swarm.behaviour_mut().blocked.block_peer(peer);
+    }
+
+    fn unban_peer(&mut self, peer: &PeerId) -> Result<bool> {
+        self.swarm.behaviour_mut().blocked.unblock_peer(*peer);
+        Ok(self.scores.unban(peer)?)
+    }
+
+    fn record_success(&mut self, peer: PeerId) {
+        self.scores.record_success(peer);
+        if !self.scores.should_shun(&peer) {
+            self.swarm.behaviour_mut().bitswap.unshun(&peer);
+        }
+    }
+
+    fn record_failure(&mut self, peer: PeerId, reason: &str) {
+        if self.scores.is_banned(&peer) {
+            return;
+        }
+        self.scores.record_failure(peer);
+        self.rescore(peer, reason);
+    }
+
+    fn record_invalid(&mut self, peer: PeerId, reason: &str) {
+        if self.scores.is_banned(&peer) {
+            return;
+        }
+        self.scores.record_invalid(peer);
+        self.rescore(peer, reason);
+    }
+
+    /// Bans `peer` if it has fallen far enough, or else stops asking it for
+    /// blocks while it's doing badly.
+    fn rescore(&mut self, peer: PeerId, reason: &str) {
+        if self.scores.should_ban(&peer) {
+            self.ban_peer(peer, reason.to_string(), Some(AUTO_BAN_DURATION));
+        } else if self.scores.should_shun(&peer) {
+            self.swarm.behaviour_mut().bitswap.shun(peer);
+        }
+    }
+
+    fn lift_expired_bans(&mut self) {
+        let expired = match self.scores.take_expired() {
+            Ok(expired) => expired,
+            Err(e) => {
+                warn!("Failed to lift expired bans: {:?}", e);
+                return;
+            }
+        };
+        for peer in expired {
+            info!("Ban on {:?} has expired", peer);
+            self.swarm.behaviour_mut().blocked.unblock_peer(peer);
+        }
+    }
+
+    /// Dials the best scoring providers we aren't connected to yet, so
+    /// bitswap has them to ask. Bitswap asks every connected peer that isn't
+    /// shunned, see `Bitswap`.
+    fn dial_best_providers(&mut self, providers: &HashSet<PeerId>) {
+        const MAX_PROVIDER_DIALS: usize = 5;
+        let local_peer_id = *self.swarm.local_peer_id();
+        let candidates = providers
+            .iter()
+            .copied()
+            .filter(|peer| *peer != local_peer_id && !self.swarm.is_connected(peer))
+            .collect::<Vec<_>>();
+        for peer in self
+            .scores
+            .rank(candidates.into_iter())
+            .into_iter()
+            .take(MAX_PROVIDER_DIALS)
+        {
+            let opts = DialOpts::peer_id(peer)
+                .condition(PeerCondition::DisconnectedAndNotDialing)
+                .build();
+            if let Err(e) = self.swarm.dial(opts) {
+                warn!("Failed to dial provider {:?}: {:?}", peer, e);
+            }
+        }
+    }
+
+    fn set_node_type(&mut self, node_type: NodeType) {
+        info!(
+            "Switching role from {:?} to {:?}",
+            self.node_type, node_type
+        );
+        self.node_type = node_type;
+        self.apply_role();
+        // Let identified peers know about the change.
+        let peers: Vec<PeerId> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.swarm
+                .behaviour_mut()
+                .role
+                .send_request(&peer, node_type);
+        }
+    }
+
+    fn stop_providing(&mut self, cids: &[Cid]) {
+        for cid in cids {
+            self.swarm
+                .behaviour_mut()
+                .kademlia
+                .stop_providing(&RecordKey::new(&cid.to_bytes()));
+        }
+    }
+
+    /// Takes on a replica if we're a distributor, the request comes from a
+    /// known provider, there's room for it and not too many others are
+    /// already being fetched. Fetched blocks are provided once they arrive,
+    /// see the bitswap event handling.
+    async fn accept_replication(
+        &mut self,
+        peer: PeerId,
+        cid: Cid,
+        size: Option<u64>,
+    ) -> Result<()> {
+        if self.node_type != NodeType::Distributor {
+            return Err(anyhow!("only distributors take on replicas"));
+        }
+        if self.shutting_down {
+            return Err(anyhow!("shutting down"));
+        }
+        let role = self.peers.get(&peer).and_then(|known| known.node_type);
+        if role != Some(NodeType::Provider) {
+            return Err(anyhow!("{:?} isn't a known provider", peer));
+        }
+        if self.blockstore.has(&cid).await? {
+            self.swarm
+                .behaviour_mut()
+                .kademlia
+                .start_providing(RecordKey::new(&cid.to_bytes()))?;
+            return Ok(());
+        }
+        if self.replications.len() >= MAX_REPLICATIONS {
+            return Err(anyhow!(
+                "{} replications already in flight",
+                MAX_REPLICATIONS
+            ));
+        }
+        let needed = size.unwrap_or(UNKNOWN_REPLICA_SIZE);
+        let Some(evicted) = self.cache.make_room(needed).await? else {
+            return Err(anyhow!("no room for {} more bytes", needed));
+        };
+        self.stop_providing(&evicted);
+
+        let query_id = self.swarm.behaviour_mut().bitswap.get(&cid);
+        self.queries.insert(query_id, cid);
+        self.replications.insert(query_id, (peer, Instant::now()));
+        Ok(())
+    }
+
+    /// Gives up on replicas nobody has served in time, so they don't hold a
+    /// slot for good. The provider that asked should have had the blocks.
+    fn expire_replications(&mut self) {
+        let expired: Vec<(beetswap::QueryId, PeerId)> = self
+            .replications
+            .iter()
+            .filter(|(_, (_, accepted))| accepted.elapsed() > REPLICATION_TIMEOUT)
+            .map(|(query_id, (provider, _))| (*query_id, *provider))
+            .collect();
+        for (query_id, provider) in expired {
+            self.replications.remove(&query_id);
+            self.swarm.behaviour_mut().bitswap.cancel(query_id);
+            if let Some(cid) = self.queries.remove(&query_id) {
+                warn!("Gave up replicating {}: no peer served it in time", cid);
+            }
+            self.record_failure(provider, "Didn't serve blocks it asked us to replicate");
+        }
+    }
+
+    async fn handle_replicate_event(
+        &mut self,
+        event: request_response::Event<ReplicationRequest, ReplicationResponse>,
+    ) {
+        match event {
+            request_response::Event::Message { peer, message } => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    let response = match request.cid.parse::<Cid>() {
+                        Ok(cid) => match self.accept_replication(peer, cid, request.size).await {
+                            Ok(()) => {
+                                info!("Replicating {} for {:?}", cid, peer);
+                                ReplicationResponse::Accepted
                             }
                             Err(e) => {
-                                warn!("Error Dialing peer: {:?}\n", e);
+                                info!("Declined to replicate {} for {:?}: {}", cid, peer, e);
+                                ReplicationResponse::Declined
                             }
+                        },
+                        Err(_) => ReplicationResponse::Declined,
+                    };
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .replicate
+                        .send_response(channel, response);
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let Some(sender) = self.pending_replications.remove(&request_id) {
+                        let _ = sender.send(Ok(matches!(response, ReplicationResponse::Accepted)));
+                    }
+                }
+            },
+            request_response::Event::OutboundFailure {
+                request_id, error, ..
+            } => {
+                if let Some(sender) = self.pending_replications.remove(&request_id) {
+                    let _ = sender.send(Err(BoxPeerError::Network(format!(
+                        "Replication request failed: {:?}",
+                        error
+                    ))
+                    .into()));
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                warn!("Replication request from {:?} failed: {:?}", peer, error);
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Keeps peers outside our protocol compatibility window out of the DHT
+    /// and role exchange, and adds the rest to the DHT when they speak one
+    /// of our Kademlia versions.
+    fn handle_identify(&mut self, peer_id: PeerId, info: identify::Info) {
+        let compatible = self.network.is_compatible(&info.protocol_version);
+        if compatible {
+            info!(
+                "Identified {:?} running {} ({})",
+                peer_id, info.agent_version, info.protocol_version
+            );
+        } else {
+            // Stay connected: relays, AutoNAT servers and the like needn't
+            // run our protocols to be useful.
+            debug!(
+                "Not routing through {:?}: incompatible protocol {} ({})",
+                peer_id, info.protocol_version, info.agent_version
+            );
+            self.swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+        }
+
+        let kad_protocols = self.network.kad_protocols();
+        if compatible && info.protocols.iter().any(|p| kad_protocols.contains(p)) {
+            for address in info.listen_addrs.iter().filter(|a| is_global(a)) {
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peer_id, address.clone());
+            }
+        }
+        if compatible && info.protocols.contains(&self.network.role_protocol()) {
+            self.swarm
+                .behaviour_mut()
+                .role
+                .send_request(&peer_id, self.node_type);
+        }
+        let known = self.peers.entry(peer_id).or_default();
+        known.identity = Some(PeerIdentity {
+            agent_version: info.agent_version,
+            protocol_version: info.protocol_version,
+            protocols: info.protocols.iter().map(|p| p.to_string()).collect(),
+            observed_addr: info.observed_addr,
+        });
+        known.listen_addrs = info.listen_addrs;
+    }
+
+    fn handle_ping(&mut self, event: ping::Event) {
+        match event.result {
+            Ok(rtt) => {
+                self.scores.record_latency(event.peer, rtt);
+                if self.swarm.is_connected(&event.peer) {
+                    self.peers.entry(event.peer).or_default().rtt = Some(rtt);
+                }
+            }
+            Err(e) => warn!("Failed to ping {:?}: {:?}", event.peer, e),
+        }
+    }
+
+    fn handle_role_event(&mut self, event: request_response::Event<NodeType, NodeType>) {
+        match event {
+            request_response::Event::Message { peer, message } => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    self.peers.entry(peer).or_default().node_type = Some(request);
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .role
+                        .send_response(channel, self.node_type);
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    self.peers.entry(peer).or_default().node_type = Some(response);
+                    self.resolve_provider_role(request_id, peer);
+                }
+            },
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                // Usually just an unreachable peer, which isn't misbehaviour.
+                warn!("Failed to exchange roles with {:?}: {:?}", peer, error);
+                self.resolve_provider_role(request_id, peer);
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                warn!("Failed to exchange roles with {:?}: {:?}", peer, error);
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    fn handle_get_providers(
+        &mut self,
+        id: kad::QueryId,
+        result: kad::GetProvidersResult,
+        last: bool,
+    ) {
+        let mut timed_out = false;
+        match result {
+            Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                if self.kad_queries.contains_key(&id) {
+                    if !providers.is_empty() {
+                        self.kad_queries_with_providers.insert(id);
+                    }
+                    self.dial_best_providers(&providers);
+                }
+                for provider in providers {
+                    self.report_provider(id, provider);
+                }
+            }
+            Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+            Err(e) => {
+                warn!("Provider lookup failed: {:?}", e);
+                timed_out = true;
+            }
+        }
+        if last {
+            let found = self.kad_queries_with_providers.remove(&id);
+            if let Some(cid) = self.kad_queries.remove(&id) {
+                // Content is only discoverable through provider records, so
+                // a lookup that found none means nobody can send it to us.
+                if !found {
+                    let error = if timed_out {
+                        BoxPeerError::Timeout(format!("Looking up providers for {}", cid))
+                    } else {
+                        BoxPeerError::NotFound(format!("No providers for {}", cid))
+                    };
+                    self.fail_fetch(cid, error);
+                }
+            }
+            if let Some(search) = self.provider_searches.get_mut(&id) {
+                search.finished = true;
+            }
+            self.close_provider_search(id);
+        }
+    }
+
+    /// Fails every file request for `cid` and cancels its bitswap queries,
+    /// so a block turning up later isn't stored and provided for nobody.
+    /// Replications of `cid` carry on; they don't rely on the lookup.
+    fn fail_fetch(&mut self, cid: Cid, error: BoxPeerError) {
+        let query_ids: Vec<beetswap::QueryId> = self
+            .queries
+            .iter()
+            .filter(|(query_id, query_cid)| {
+                **query_cid == cid && self.pending_requests.contains_key(query_id)
+            })
+            .map(|(query_id, _)| *query_id)
+            .collect();
+        for query_id in query_ids {
+            self.queries.remove(&query_id);
+            self.swarm.behaviour_mut().bitswap.cancel(query_id);
+            if let Some(sender) = self.pending_requests.remove(&query_id) {
+                reply(sender, Err(error.clone().into()), "file error");
+            }
+        }
+    }
+
+    /// Passes a newly found provider on to its search. Providers whose role we
+    /// don't know yet are asked for it first, which also dials them.
+    fn report_provider(&mut self, id: kad::QueryId, provider: PeerId) {
+        let Some(search) = self.provider_searches.get_mut(&id) else {
+            return;
+        };
+        if !search.seen.insert(provider) {
+            return;
+        }
+        let known_role = self.peers.get(&provider).and_then(|known| known.node_type);
+        let info = if provider == *self.swarm.local_peer_id() {
+            Some(self.local_peer_info())
+        } else if known_role.is_some() {
+            Some(self.provider_info(&provider))
+        } else {
+            None
+        };
+        match info {
+            Some(info) => {
+                if let Some(search) = self.provider_searches.get(&id) {
+                    let _ = search.sender.unbounded_send(info);
+                }
+            }
+            None => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .role
+                    .send_request(&provider, self.node_type);
+                self.provider_role_requests.insert(request_id, id);
+                if let Some(search) = self.provider_searches.get_mut(&id) {
+                    search.pending_roles += 1;
+                }
+            }
+        }
+    }
+
+    fn resolve_provider_role(
+        &mut self,
+        request_id: request_response::OutboundRequestId,
+        peer: PeerId,
+    ) {
+        let Some(id) = self.provider_role_requests.remove(&request_id) else {
+            return;
+        };
+        let info = self.provider_info(&peer);
+        if let Some(search) = self.provider_searches.get_mut(&id) {
+            search.pending_roles -= 1;
+            let _ = search.sender.unbounded_send(info);
+        }
+        self.close_provider_search(id);
+    }
+
+    /// Drops a finished search, which ends the caller's stream.
+    fn close_provider_search(&mut self, id: kad::QueryId) {
+        if self
+            .provider_searches
+            .get(&id)
+            .is_some_and(|search| search.finished && search.pending_roles == 0)
+        {
+            self.provider_searches.remove(&id);
+        }
+    }
+
+    /// Like `peer_info`, but falls back to the routing table for addresses
+    /// of providers we couldn't reach.
+    fn provider_info(&mut self, peer_id: &PeerId) -> PeerInfo {
+        let mut info = self.peer_info(peer_id);
+        if info.addresses.is_empty() {
+            if let Some(bucket) = self.swarm.behaviour_mut().kademlia.kbucket(*peer_id) {
+                info.addresses = bucket
+                    .iter()
+                    .find(|entry| entry.node.key.preimage() == peer_id)
+                    .map(|entry| entry.node.value.iter().map(|a| a.to_string()).collect())
+                    .unwrap_or_default();
+            }
+        }
+        info
+    }
+
+    fn local_peer_info(&self) -> PeerInfo {
+        let local_peer_id = self.swarm.local_peer_id();
+        PeerInfo {
+            peer_id: local_peer_id.to_string(),
+            addresses: self
+                .swarm
+                .external_addresses()
+                .chain(self.swarm.listeners())
+                .map(|a| a.to_string())
+                .collect(),
+            node_type: Some(self.node_type),
+            score: self.scores.score(local_peer_id),
+        }
+    }
+
+    /// Only forwards announcements whose uploader signature checks out.
+    fn handle_announcement(
+        &mut self,
+        source: PeerId,
+        message_id: gossipsub::MessageId,
+        message: gossipsub::Message,
+    ) {
+        let acceptance = if message.topic != self.network.announce_topic().hash() {
+            gossipsub::MessageAcceptance::Ignore
+        } else {
+            match announce::verify(&message.data) {
+                Ok((manifest, uploader)) => {
+                    info!(
+                        "{:?} announced {} ({} bytes)",
+                        uploader, manifest.cid, manifest.size
+                    );
+                    self.announcements.push(manifest, uploader);
+                    gossipsub::MessageAcceptance::Accept
+                }
+                Err(e) => {
+                    warn!("Rejecting announcement from {:?}: {:?}", source, e);
+                    self.record_invalid(source, "Forwarded invalid announcements");
+                    gossipsub::MessageAcceptance::Reject
+                }
+            }
+        };
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(&message_id, &source, acceptance);
+    }
+
+    /// Prefers the addresses a peer listens on, falling back to the ones we
+    /// are connected to it over until identify has run.
+    fn peer_info(&self, peer_id: &PeerId) -> PeerInfo {
+        let known = self.peers.get(peer_id);
+        let addresses = match known {
+            Some(known) if !known.listen_addrs.is_empty() => known.listen_addrs.clone(),
+            _ => self
+                .connections
+                .get(peer_id)
+                .map(|connections| {
+                    connections
+                        .values()
+                        .map(|connection| connection.address.clone())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        PeerInfo {
+            peer_id: peer_id.to_string(),
+            addresses: addresses.iter().map(|a| a.to_string()).collect(),
+            node_type: known.and_then(|known| known.node_type),
+            score: self.scores.score(peer_id),
+        }
+    }
+
+    fn peer_details(&self, peer_id: &PeerId) -> Option<PeerDetails> {
+        let connections = self.connections.get(peer_id)?;
+        let known = self.peers.get(peer_id);
+        let identity = known.and_then(|known| known.identity.as_ref());
+        Some(PeerDetails {
+            peer_id: peer_id.to_string(),
+            listen_addrs: known
+                .map(|known| known.listen_addrs.iter().map(|a| a.to_string()).collect())
+                .unwrap_or_default(),
+            observed_addr: identity.map(|identity| identity.observed_addr.to_string()),
+            protocols: identity
+                .map(|identity| identity.protocols.clone())
+                .unwrap_or_default(),
+            agent_version: identity.map(|identity| identity.agent_version.clone()),
+            protocol_version: identity.map(|identity| identity.protocol_version.clone()),
+            rtt_ms: known
+                .and_then(|known| known.rtt)
+                .map(|rtt| rtt.as_secs_f64() * 1000.0),
+            connections: connections
+                .values()
+                .map(|connection| ConnectionDetails {
+                    address: connection.address.to_string(),
+                    transport: transport_name(&connection.address).to_string(),
+                    direction: connection.direction,
+                    uptime_secs: connection.since.elapsed().as_secs(),
+                })
+                .collect(),
+            node_type: known.and_then(|known| known.node_type),
+            score: self.scores.score(peer_id),
+        })
+    }
+
+    fn handle_upnp_event(&mut self, event: upnp::Event) {
+        match event {
+            upnp::Event::NewExternalAddr(address) => {
+                info!("Router forwards {} to us over UPnP", address);
+            }
+            upnp::Event::ExpiredExternalAddr(address) => {
+                warn!("UPnP mapping for {} expired", address);
+            }
+            upnp::Event::GatewayNotFound => info!("No UPnP gateway found"),
+            upnp::Event::NonRoutableGateway => {
+                info!("UPnP gateway is itself behind NAT, not mapping ports")
+            }
+        }
+    }
+
+    fn handle_relay_server_event(&mut self, event: relay::Event) {
+        let stats = &mut self.relay_stats;
+        match event {
+            relay::Event::ReservationReqAccepted { src_peer_id, .. } => {
+                stats.reservations_accepted += 1;
+                self.relay_reservations.insert(src_peer_id);
+            }
+            relay::Event::ReservationReqDenied { src_peer_id } => {
+                stats.reservations_denied += 1;
+                info!("Denied relay reservation for {:?}", src_peer_id);
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                self.relay_reservations.remove(&src_peer_id);
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                stats.circuits_accepted += 1;
+                stats.active_circuits += 1;
+                info!("Relaying {:?} -> {:?}", src_peer_id, dst_peer_id);
+            }
+            relay::Event::CircuitReqDenied { .. } => {
+                stats.circuits_denied += 1;
+            }
+            relay::Event::CircuitClosed { .. } => {
+                stats.circuits_closed += 1;
+                stats.active_circuits = stats.active_circuits.saturating_sub(1);
+            }
+            _ => {}
+        }
+        stats.active_reservations = self.relay_reservations.len();
+    }
+
+    /// Reacts to AutoNAT's view of our reachability. Behind a NAT we can't
+    /// answer DHT queries, so we drop to client mode and reserve a slot on
+    /// our relays instead so others can still reach us (and hole punch).
+    fn handle_nat_status(&mut self, status: autonat::NatStatus) {
+        self.apply_role();
+        match status {
+            autonat::NatStatus::Public(address) => {
+                info!("Node is publicly reachable at {:?}", address);
+                for listener in self.relay_listeners.drain(..) {
+                    self.swarm.remove_listener(listener);
+                }
+                self.relay_addresses.clear();
+            }
+            autonat::NatStatus::Private => {
+                info!("Node is not publicly reachable, using relays");
+                if self.relay_listeners.is_empty() {
+                    for relay in &self.relays {
+                        let address = relay.clone().with(Protocol::P2pCircuit);
+                        match self.swarm.listen_on(address) {
+                            Ok(listener) => self.relay_listeners.push(listener),
+                            Err(e) => warn!("Failed to listen via relay {}: {:?}", relay, e),
                         }
                     }
                 }
-                kad::Event::OutboundQueryProgressed { id, result, .. } => {
-                    if let kad::QueryResult::GetProviders(Ok(
-                        kad::GetProvidersOk::FoundProviders { providers, .. },
-                    )) = result
+            }
+            autonat::NatStatus::Unknown => {}
+        }
+    }
+
+    async fn handle_event(
+        &mut self,
+        event: SwarmEvent<BehaviourEvent>,
+    ) -> Result<(), anyhow::Error> {
+        match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Bitswap(bitswap)) => match bitswap {
+                bitswap::Event::GetQueryResponse {
+                    query_id,
+                    data,
+                    from,
+                } => {
+                    let cid = self.queries.remove(&query_id);
+                    self.replications.remove(&query_id);
+                    if let Some(cid) = cid {
+                        // The block is stored either way; an untracked one
+                        // just can't be evicted.
+                        if let Err(e) = self.cache.touch(&cid, data.len() as u64) {
+                            warn!("Failed to track cached block {}: {:?}", cid, e);
+                        }
+                    }
+                    if let (Some(cid), Some((peer, rtt))) = (cid, from) {
+                        // The data matches its CID or bitswap wouldn't have
+                        // taken it, but it should also decode as that CID's
+                        // codec says.
+                        if car::links(&cid, &data).is_err() {
+                            self.record_invalid(peer, "Sent blocks that don't decode");
+                        } else {
+                            self.record_success(peer);
+                            self.scores.record_latency(peer, rtt);
+                        }
+                    }
+                    // Distributors act as caches for whatever passes through them.
+                    if let (Some(cid), NodeType::Distributor, false) =
+                        (cid, self.node_type, self.shutting_down)
                     {
-                        if let Some(sender) = self.pending_get_providers.remove(&id) {
-                            sender.send(providers).expect("Receiver not to be dropped");
-                            self.swarm
-                                .behaviour_mut()
-                                .kademlia
-                                .query_mut(&id)
-                                .unwrap()
-                                .finish();
+                        if let Err(e) = self
+                            .swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .start_providing(RecordKey::new(&cid.to_bytes()))
+                        {
+                            warn!("Failed to provide cached {}: {:?}", cid, e);
+                        }
+                    }
+                    if let Some(sender) = self.pending_requests.remove(&query_id) {
+                        reply(sender, Ok(data), "file data");
+                    }
+                }
+                bitswap::Event::WantFailed { peer, reason } => self.record_failure(peer, reason),
+                bitswap::Event::GetQueryError { query_id, error } => {
+                    self.queries.remove(&query_id);
+                    self.replications.remove(&query_id);
+                    if let Some(sender) = self.pending_requests.remove(&query_id) {
+                        reply(
+                            sender,
+                            Err(BoxPeerError::Network(format!(
+                                "Error for CID {:?}: {:?}",
+                                query_id, error
+                            ))
+                            .into()),
+                            "file error",
+                        );
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged {
+                old,
+                new,
+            })) => {
+                info!("NAT status changed from {:?} to {:?}", old, new);
+                self.handle_nat_status(new);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted { relay_peer_id, .. },
+            )) => {
+                info!("Relay reservation accepted by {:?}", relay_peer_id);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event {
+                remote_peer_id,
+                result,
+            })) => match result {
+                Ok(_) => info!("Hole punched a direct connection to {:?}", remote_peer_id),
+                Err(e) => warn!("Hole punching to {:?} failed: {:?}", remote_peer_id, e),
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::RelayServer(relay_event)) => {
+                self.handle_relay_server_event(relay_event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp_event)) => {
+                self.handle_upnp_event(upnp_event);
+            }
+            // Only confirmed addresses, from AutoNAT, UPnP or relay
+            // reservations, are advertised to the DHT.
+            SwarmEvent::ExternalAddrConfirmed { address } => {
+                if is_global(&address) {
+                    info!("External address confirmed: {}", address);
+                } else {
+                    info!("Not advertising non-global address {}", address);
+                    self.swarm.remove_external_address(&address);
+                }
+            }
+            SwarmEvent::ExternalAddrExpired { address } => {
+                info!("External address expired: {}", address);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+                ..
+            })) => {
+                self.handle_identify(peer_id, info);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(ping_event)) => {
+                self.handle_ping(ping_event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => {
+                self.handle_announcement(propagation_source, message_id, message);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Role(role_event)) => {
+                self.handle_role_event(role_event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Replicate(replicate_event)) => {
+                self.handle_replicate_event(replicate_event).await;
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns_event)) => {
+                if let mdns::Event::Discovered(peers) = mdns_event {
+                    for (peer_id, multiaddr) in peers {
+                        self.swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .add_address(&peer_id, multiaddr.clone());
+                        info!("Discovered Peer MDNS: {:?}", &multiaddr);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad_event)) => match kad_event {
+                kad::Event::RoutingUpdated {
+                    peer, addresses, ..
+                } => {
+                    info!(
+                        "Discovered peer via Kademlia: {:?} at {:?}",
+                        peer, addresses
+                    );
+                    // Only dial peers we aren't already connected or dialling
+                    // to, the connection limits take care of the rest.
+                    let opts = DialOpts::peer_id(peer)
+                        .addresses(addresses.into_vec())
+                        .condition(PeerCondition::DisconnectedAndNotDialing)
+                        .build();
+                    match self.swarm.dial(opts) {
+                        Ok(()) => {
+                            info!("Dialing peer: {:?}\n", peer);
+                        }
+                        Err(e) => {
+                            warn!("Error Dialing peer: {:?}\n", e);
                         }
                     }
                 }
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::GetProviders(result),
+                    step,
+                    ..
+                } => {
+                    self.handle_get_providers(id, result, step.last);
+                }
                 _ => {
                     info!("Other Kademlia event: {:?}", kad_event);
                 }
             },
-            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
                 warn!(
                     "Failed to connect to peer: {:?}, error: {:?}",
                     peer_id, error
                 );
+                // Other dials to the same peer, say from Kademlia, can fail
+                // while ours is still going.
+                if let Some(peer_id) = peer_id {
+                    if let hash_map::Entry::Occupied(entry) = self.pending_dial.entry(peer_id) {
+                        if entry.get().connection_id == connection_id {
+                            for sender in entry.remove().senders {
+                                let _ = sender.send(Err(BoxPeerError::Network(format!(
+                                    "Failed to connect to {}: {}",
+                                    peer_id, error
+                                ))
+                                .into()));
+                            }
+                        }
+                    }
+                }
             }
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
             } => {
-                if endpoint.is_dialer() {
-                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
+                // Any connection will do, even one the peer opened.
+                if let Some(dial) = self.pending_dial.remove(&peer_id) {
+                    for sender in dial.senders {
                         let _ = sender.send(Ok(()));
                     }
                 }
-                info!("Connection established with peer: {:?}", peer_id);
+                let address = endpoint.get_remote_address().clone();
+                info!(
+                    "Connection established with peer: {:?} over {}",
+                    peer_id,
+                    transport_name(&address)
+                );
+                let direction = if endpoint.is_dialer() {
+                    ConnectionDirection::Outbound
+                } else {
+                    ConnectionDirection::Inbound
+                };
+                self.connections.entry(peer_id).or_default().insert(
+                    connection_id,
+                    OpenConnection {
+                        address,
+                        direction,
+                        since: Instant::now(),
+                    },
+                );
             }
-            SwarmEvent::NewListenAddr { address, .. } => {
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => {
+                self.listeners.insert(listener_id);
+                if self.relay_listeners.contains(&listener_id) {
+                    self.relay_addresses.insert(address.clone());
+                }
                 let local_peer_id = *self.swarm.local_peer_id();
                 info!(
                     "Local node is listening on {:?}",
@@ -402,7 +2028,29 @@ impl EventLoop {
                 );
             }
 
-            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                cause,
+                num_established,
+                ..
+            } => {
+                if num_established == 0 {
+                    for sender in self
+                        .pending_disconnects
+                        .remove(&peer_id)
+                        .unwrap_or_default()
+                    {
+                        let _ = sender.send(Ok(()));
+                    }
+                }
+                if let Some(connections) = self.connections.get_mut(&peer_id) {
+                    connections.remove(&connection_id);
+                    if connections.is_empty() {
+                        self.connections.remove(&peer_id);
+                        self.peers.remove(&peer_id);
+                    }
+                }
                 println!(
                     "Connection closed with peer: {:?}, reason: {:?}",
                     peer_id, cause
@@ -437,11 +2085,21 @@ impl EventLoop {
             }
             SwarmEvent::ExpiredListenAddr { address, .. } => {
                 println!("Listen address expired: {:?}", address);
-                self.swarm.listen_on(address).expect("Error listening");
+                if let Err(e) = self.swarm.listen_on(address.clone()) {
+                    warn!("Failed to listen on {} again: {:?}", address, e);
+                }
             }
             SwarmEvent::ListenerClosed {
-                addresses, reason, ..
+                listener_id,
+                addresses,
+                reason,
             } => {
+                self.listeners.remove(&listener_id);
+                // Lets the next private NAT status retry the reservation.
+                self.relay_listeners.retain(|id| *id != listener_id);
+                for address in &addresses {
+                    self.relay_addresses.remove(address);
+                }
                 warn!(
                     "Listener closed for addresses: {:?}, reason: {:?}",
                     addresses, reason
@@ -464,40 +2122,49 @@ impl EventLoop {
         Ok(())
     }
 
-    async fn handle_command(&mut self, command: Command) -> Result<(), anyhow::Error> {
-        match command {
-            Command::UploadFile { file_path, sender } => {
-                // Read the file as binary data
-                let file_data = fs::read(&file_path)
-                    .map_err(|e| anyhow!("Failed to read file from {:?}: {:?}", file_path, e))?;
+    /// Stores a file as a single block and starts providing it.
+    async fn store_file(&mut self, file_path: &Path) -> Result<Cid> {
+        // Read the file as binary data
+        let file_data = fs::read(file_path)
+            .map_err(|e| anyhow!("Failed to read file from {:?}: {:?}", file_path, e))?;
 
-                // Create the file block
-                let block = FileBlock(file_data);
+        // Create the file block
+        let block = FileBlock(file_data);
 
-                // Generate the CID
-                let cid = block
-                    .cid()
-                    .map_err(|e| anyhow!("Failed to generate CID: {:?}", e))?;
+        // Generate the CID
+        let cid = block
+            .cid()
+            .map_err(|e| anyhow!("Failed to generate CID: {:?}", e))?;
 
-                info!("Uploading file with CID: {}", cid);
-                self.blockstore
-                    .put_keyed(&cid, block.data())
-                    .await
-                    .map_err(|e| anyhow!("Failed to store block: {:?}", e))?;
+        info!("Uploading file with CID: {}", cid);
+        self.blockstore
+            .put_keyed(&cid, block.data())
+            .await
+            .map_err(|e| self.blockstore.put_error(e, block.data().len() as u64))?;
 
-                let cid_key = RecordKey::new(&cid.to_bytes());
-                self.swarm
-                    .behaviour_mut()
-                    .kademlia
-                    .start_providing(cid_key)
-                    .map_err(|e| anyhow!("Failed to start providing the CID: {:?}", e))?;
+        let cid_key = RecordKey::new(&cid.to_bytes());
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .start_providing(cid_key)
+            .map_err(|e| anyhow!("Failed to start providing the CID: {:?}", e))?;
+
+        Ok(cid)
+    }
 
-                // Send the CID as the result of the upload
-                sender
-                    .send(Ok(cid))
-                    .map_err(|e| anyhow!("Failed to send CID result: {:?}", e))?;
+    async fn handle_command(&mut self, command: Command) -> Result<(), anyhow::Error> {
+        match command {
+            Command::UploadFile { file_path, sender } => {
+                let result = self.store_file(&file_path).await;
+                reply(sender, result, "upload result");
             }
             Command::RequestFile { cid, sender } => {
+                // Bitswap can't see our own blocks while we aren't serving,
+                // see `BitswapStore`, and would fetch them all over again.
+                if let Ok(Some(data)) = self.blockstore.get(&cid).await {
+                    reply(sender, Ok(data), "file data");
+                    return Ok(());
+                }
                 let query_id = self.swarm.behaviour_mut().bitswap.get(&cid);
                 let kad_query_id = self
                     .swarm
@@ -510,44 +2177,342 @@ impl EventLoop {
             }
             Command::StartListening { addr, sender } => {
                 let peer_id = *self.swarm.local_peer_id();
-                self.swarm
-                    .behaviour_mut()
-                    .kademlia
-                    .add_address(&peer_id, addr.clone());
-
                 let result = self
                     .swarm
                     .listen_on(addr)
                     .map(|_| peer_id.to_string())
                     .map_err(|e| anyhow!("Failed to listen on address: {:?}", e));
-                sender
-                    .send(result)
-                    .map_err(|e| anyhow!("Failed to send start listening result: {:?}", e))?;
+                reply(sender, result, "start listening result");
             }
 
+            Command::StopProviding { cids, sender } => {
+                self.stop_providing(&cids);
+                reply(sender, (), "stop providing");
+            }
+            Command::StartProviding { cid, sender } => {
+                let result = self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .start_providing(RecordKey::new(&cid.to_bytes()))
+                    .map(|_| ())
+                    .map_err(|e| anyhow!("Failed to start providing the CID: {:?}", e));
+                reply(sender, result, "start providing result");
+            }
             Command::GetProviders { cid, sender } => {
                 let query_id = self.swarm.behaviour_mut().kademlia.get_providers(cid);
-                self.pending_get_providers.insert(query_id, sender);
+                self.provider_searches.insert(
+                    query_id,
+                    ProviderSearch {
+                        sender,
+                        seen: HashSet::new(),
+                        pending_roles: 0,
+                        finished: false,
+                    },
+                );
                 info!("Searching for providers for CID, query ID: {:?}", query_id);
             }
+            Command::Announce {
+                manifest,
+                data,
+                sender,
+            } => {
+                let result = self
+                    .swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .publish(self.network.announce_topic(), data)
+                    .map(|_| ())
+                    .map_err(|e| anyhow!("Failed to publish announcement: {:?}", e));
+                let local_peer_id = *self.swarm.local_peer_id();
+                self.announcements.push(manifest, local_peer_id);
+                reply(sender, result, "announce result");
+            }
+            Command::Dial {
+                peer_id,
+                addr,
+                sender,
+            } => {
+                if self.swarm.is_connected(&peer_id) {
+                    let _ = sender.send(Ok(()));
+                } else if let Some(dial) = self.pending_dial.get_mut(&peer_id) {
+                    dial.senders.push(sender);
+                } else {
+                    // Dial even if something else already is, so the
+                    // address asked for gets tried and the outcome is ours.
+                    let opts = DialOpts::peer_id(peer_id)
+                        .addresses(vec![addr])
+                        .condition(PeerCondition::Always)
+                        .build();
+                    let connection_id = opts.connection_id();
+                    match self.swarm.dial(opts) {
+                        Ok(()) => {
+                            self.pending_dial.insert(
+                                peer_id,
+                                PendingDial {
+                                    connection_id,
+                                    senders: vec![sender],
+                                },
+                            );
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(BoxPeerError::Network(format!(
+                                "Failed to dial {}: {}",
+                                peer_id, e
+                            ))
+                            .into()));
+                        }
+                    }
+                }
+            }
+            Command::Disconnect { peer_id, sender } => {
+                if self.swarm.disconnect_peer_id(peer_id).is_ok() {
+                    self.pending_disconnects
+                        .entry(peer_id)
+                        .or_default()
+                        .push(sender);
+                } else {
+                    let _ =
+                        sender.send(Err(BoxPeerError::NotConnected(peer_id.to_string()).into()));
+                }
+            }
+            // Handled by `run`, which stops the loop afterwards.
+            Command::Shutdown { .. } => {}
+            Command::BanPeer { peer, sender } => {
+                self.ban_peer(peer, "Banned by operator".to_string(), None);
+                reply(sender, (), "ban");
+            }
+            Command::UnbanPeer { peer, sender } => {
+                let was_banned = self.unban_peer(&peer);
+                reply(sender, was_banned, "unban");
+            }
+            Command::ListBanned { sender } => {
+                reply(sender, self.scores.banned(), "banned peers");
+            }
+            Command::GetNodeType { sender } => {
+                reply(sender, self.node_type, "node type");
+            }
+            Command::SetNodeType { node_type, sender } => {
+                self.set_node_type(node_type);
+                reply(sender, (), "node type change");
+            }
+            Command::RequestReplication {
+                peer,
+                cid,
+                size,
+                sender,
+            } => {
+                let request_id = self.swarm.behaviour_mut().replicate.send_request(
+                    &peer,
+                    ReplicationRequest {
+                        cid: cid.to_string(),
+                        size,
+                    },
+                );
+                self.pending_replications.insert(request_id, sender);
+            }
+            Command::GetAnnouncements { sender } => {
+                reply(sender, self.announcements.list(), "announcements");
+            }
+            Command::GetRelayStats { sender } => {
+                let stats = RelayStats {
+                    enabled: self.swarm.behaviour().relay_server.is_enabled(),
+                    ..self.relay_stats.clone()
+                };
+                reply(sender, stats, "relay stats");
+            }
+            Command::GetNatInfo { sender } => {
+                let (status, public_address) = match self.swarm.behaviour().autonat.nat_status() {
+                    autonat::NatStatus::Public(address) => ("public", Some(address.to_string())),
+                    autonat::NatStatus::Private => ("private", None),
+                    autonat::NatStatus::Unknown => ("unknown", None),
+                };
+                let info = NatInfo {
+                    status: status.to_string(),
+                    public_address,
+                    relay_addresses: self.relay_addresses.iter().map(|a| a.to_string()).collect(),
+                };
+                reply(sender, info, "NAT info");
+            }
+            Command::GetPeerInfo { peer_id, sender } => {
+                reply(sender, self.peer_details(&peer_id), "peer info");
+            }
+            Command::GetPeerTransports { sender } => {
+                let peers = self
+                    .connections
+                    .iter()
+                    .map(|(peer_id, connections)| {
+                        let mut transports: Vec<String> = connections
+                            .values()
+                            .map(|connection| transport_name(&connection.address).to_string())
+                            .collect();
+                        transports.sort();
+                        transports.dedup();
+                        PeerTransports {
+                            peer_id: peer_id.to_string(),
+                            transports,
+                        }
+                    })
+                    .collect();
+                reply(sender, peers, "peer transports");
+            }
             Command::GetPeers { sender } => {
-                let peers: Vec<PeerId> = self.swarm.connected_peers().cloned().collect();
-                sender
-                    .send(Ok(peers))
-                    .map_err(|e| anyhow!("Failed to send peers: {:?}", e))?;
+                let peers = self
+                    .swarm
+                    .connected_peers()
+                    .map(|peer_id| self.peer_info(peer_id))
+                    .collect();
+                reply(sender, peers, "peers");
             }
         }
 
         Ok(())
     }
 
-    pub async fn run(mut self) {
+    /// Winds the node down for `Command::Shutdown`. Requests still queued or
+    /// in flight get an error, then we close every listener and connection,
+    /// give peers a moment to see them go and flush the database.
+    async fn shutdown(&mut self, sender: oneshot::Sender<()>) {
+        const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+        info!("Shutting down");
+
+        self.command_receiver.close();
+        while let Ok(Some(command)) = self.command_receiver.try_next() {
+            // Dropping the command drops its reply channel, which fails the request.
+            drop(command);
+        }
+        for (_, sender) in self.pending_requests.drain() {
+            let _ = sender.send(Err(BoxPeerError::Cancelled.into()));
+        }
+        for (_, dial) in self.pending_dial.drain() {
+            for sender in dial.senders {
+                let _ = sender.send(Err(BoxPeerError::Cancelled.into()));
+            }
+        }
+        for (_, sender) in self.pending_replications.drain() {
+            let _ = sender.send(Err(BoxPeerError::Cancelled.into()));
+        }
+        for sender in self
+            .pending_disconnects
+            .drain()
+            .flat_map(|(_, senders)| senders)
+        {
+            let _ = sender.send(Ok(()));
+        }
+        // Ends every find_providers stream.
+        self.provider_searches.clear();
+        self.provider_role_requests.clear();
+        self.queries.clear();
+        self.kad_queries.clear();
+
+        // Withdraw our provider records so Kademlia doesn't republish them
+        // while connections wind down, and take on nothing new to provide.
+        self.shutting_down = true;
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        let provided: Vec<RecordKey> = kademlia
+            .store_mut()
+            .provided()
+            .map(|record| record.key.clone())
+            .collect();
+        for key in &provided {
+            kademlia.stop_providing(key);
+        }
+
+        for listener in self.listeners.drain() {
+            self.swarm.remove_listener(listener);
+        }
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer in peers {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+        let closed = tokio::time::timeout(SHUTDOWN_GRACE, async {
+            while self.swarm.connected_peers().next().is_some() {
+                let event = self.swarm.select_next_some().await;
+                if let Err(e) = self.handle_event(event).await {
+                    warn!("Error handling event during shutdown: {:?}", e);
+                }
+            }
+        })
+        .await;
+        if closed.is_err() {
+            warn!("Some connections didn't close in time");
+        }
+
+        // The blockstore syncs whichever backend holds the blocks, then
+        // marks its counters clean; the database flush makes that stick.
+        if let Err(e) = self.blockstore.flush().await {
+            warn!("Failed to flush blockstore: {:?}", e);
+        }
+        if let Err(e) = self.db.flush_async().await {
+            warn!("Failed to flush database: {:?}", e);
+        }
+        info!("Shutdown complete");
+        let _ = sender.send(());
+    }
+
+    /// Runs the event loop until shutdown, restarting it with the same swarm
+    /// and state if it panics. A loop that keeps panicking right after a
+    /// restart is given up on rather than spun forever, and the error says
+    /// why; from then on every request fails with `NetworkUnavailable`.
+    pub async fn run(mut self) -> Result<()> {
+        const MAX_QUICK_RESTARTS: u32 = 5;
+        const QUICK_RESTART_WINDOW: Duration = Duration::from_secs(60);
+        let mut quick_restarts = 0;
+        loop {
+            let started = Instant::now();
+            let Err(panic) = AssertUnwindSafe(self.run_until_stopped())
+                .catch_unwind()
+                .await
+            else {
+                return Ok(());
+            };
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            if started.elapsed() < QUICK_RESTART_WINDOW {
+                quick_restarts += 1;
+            } else {
+                quick_restarts = 1;
+            }
+            if quick_restarts > MAX_QUICK_RESTARTS {
+                error!("Event loop panicked again ({}), giving up", message);
+                self.command_receiver.close();
+                return Err(anyhow!(
+                    "The network stopped after failing {} times in a row: {}",
+                    quick_restarts,
+                    message
+                ));
+            }
+            error!("Event loop panicked ({}), restarting", message);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn run_until_stopped(&mut self) {
+        let mut ban_sweep = tokio::time::interval(BAN_SWEEP_INTERVAL);
+        let mut replication_sweep = tokio::time::interval(REPLICATION_SWEEP_INTERVAL);
         loop {
             select! {
-                event = self.swarm.select_next_some() => self.handle_event(event).await.expect("Error handling event"),
+                _ = ban_sweep.tick() => self.lift_expired_bans(),
+                _ = replication_sweep.tick() => self.expire_replications(),
+                event = self.swarm.select_next_some() => {
+                    if let Err(e) = self.handle_event(event).await {
+                        warn!("Error handling event: {:?}", e);
+                    }
+                }
                 command = self.command_receiver.next() => match command {
-                    Some(c) => self.handle_command(c).await.expect("Error handling command"),
-                    None=>  return,
+                    Some(Command::Shutdown { sender }) => {
+                        self.shutdown(sender).await;
+                        return;
+                    }
+                    Some(c) => {
+                        if let Err(e) = self.handle_command(c).await {
+                            warn!("Error handling command: {:?}", e);
+                        }
+                    }
+                    None => return,
                 },
             }
         }
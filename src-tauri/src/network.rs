@@ -0,0 +1,179 @@
+use crate::config::NodeConfig;
+use libp2p::pnet::PreSharedKey;
+use libp2p::{gossipsub, StreamProtocol};
+use std::error::Error;
+use std::fs;
+
+const PROTOCOL_PREFIX: &str = "/boxpeer";
+
+/// Version of the BoxPeer protocol family advertised through identify.
+const PROTOCOL_VERSION: (u64, u64, u64) = (1, 0, 0);
+/// Oldest version we still talk to. Peers must share our major version and
+/// be at least this recent; anything else is left out of routing and role
+/// exchange after identify.
+const MIN_COMPATIBLE_VERSION: (u64, u64, u64) = (1, 0, 0);
+/// The unversioned name releases before the protocol family used for both
+/// Kademlia and identify. Only spoken when `legacy_protocol` is set.
+const LEGACY_PROTOCOL: &str = "/ipfs/0.1.0";
+// Support for `LEGACY_PROTOCOL` and its config flag is removed with the next
+// major protocol version.
+const _: () = assert!(
+    PROTOCOL_VERSION.0 < 2,
+    "remove LEGACY_PROTOCOL and the legacy_protocol config flag"
+);
+
+/// Kademlia protocol versions we speak, newest first. Multistream-select
+/// settles on the first one both sides support, so older versions stay in
+/// this list while nodes that only speak them are still around.
+const KAD_PROTOCOL_VERSIONS: &[&str] = &["1.0.0"];
+
+/// The BoxPeer network a node belongs to: the public one, or a private one
+/// set apart by a pre-shared swarm key.
+#[derive(Clone, Copy)]
+pub(crate) struct Network {
+    psk: Option<PreSharedKey>,
+    legacy: bool,
+}
+
+impl Network {
+    /// Reads the swarm key named in the config, if any. The file uses the
+    /// go-ipfs `swarm.key` format, so keys can be shared with other tooling.
+    pub(crate) fn load(config: &NodeConfig) -> Result<Self, Box<dyn Error>> {
+        let psk = match &config.swarm_key_path {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read swarm key {:?}: {}", path, e))?;
+                Some(
+                    contents
+                        .parse::<PreSharedKey>()
+                        .map_err(|e| format!("Invalid swarm key {:?}: {}", path, e))?,
+                )
+            }
+            None => None,
+        };
+        // Legacy nodes never used swarm keys, so private networks have none.
+        let legacy = config.legacy_protocol && psk.is_none();
+        Ok(Self { psk, legacy })
+    }
+
+    pub(crate) fn psk(&self) -> Option<PreSharedKey> {
+        self.psk
+    }
+
+    /// Private networks put their key's fingerprint in every protocol name so
+    /// their routing tables never mix with other networks.
+    fn prefix(&self) -> String {
+        match self.psk {
+            Some(psk) => format!("{}/{}", PROTOCOL_PREFIX, psk.fingerprint()),
+            None => PROTOCOL_PREFIX.to_string(),
+        }
+    }
+
+    /// Kademlia protocol names in order of preference, the legacy one last.
+    pub(crate) fn kad_protocols(&self) -> Vec<StreamProtocol> {
+        KAD_PROTOCOL_VERSIONS
+            .iter()
+            .map(|version| format!("{}/kad/{}", self.prefix(), version))
+            .chain(self.legacy.then(|| LEGACY_PROTOCOL.to_string()))
+            .map(|name| {
+                StreamProtocol::try_from_owned(name).expect("protocol name starts with a slash")
+            })
+            .collect()
+    }
+
+    /// Protocol peers use to tell each other their `NodeType`.
+    pub(crate) fn role_protocol(&self) -> StreamProtocol {
+        StreamProtocol::try_from_owned(format!("{}/role/1.0.0", self.prefix()))
+            .expect("protocol name starts with a slash")
+    }
+
+    /// Protocol for asking a distributor to replicate content.
+    pub(crate) fn replicate_protocol(&self) -> StreamProtocol {
+        StreamProtocol::try_from_owned(format!("{}/replicate/1.0.0", self.prefix()))
+            .expect("protocol name starts with a slash")
+    }
+
+    /// Gossipsub topic new content is announced on.
+    pub(crate) fn announce_topic(&self) -> gossipsub::IdentTopic {
+        gossipsub::IdentTopic::new(format!("{}/announce/1.0.0", self.prefix()))
+    }
+
+    /// Protocol version sent in identify, e.g. `/boxpeer/1.0.0`.
+    pub(crate) fn protocol_version(&self) -> String {
+        let (major, minor, patch) = PROTOCOL_VERSION;
+        format!("{}/{}.{}.{}", self.prefix(), major, minor, patch)
+    }
+
+    /// Whether a peer advertising `protocol_version` in identify is on our
+    /// network and within the compatibility window.
+    pub(crate) fn is_compatible(&self, protocol_version: &str) -> bool {
+        if protocol_version == LEGACY_PROTOCOL {
+            return self.legacy;
+        }
+        let Some(version) = protocol_version
+            .strip_prefix(&self.prefix())
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(parse_version)
+        else {
+            return false;
+        };
+        version.0 == PROTOCOL_VERSION.0 && version >= MIN_COMPATIBLE_VERSION
+    }
+}
+
+/// Agent version sent in identify so peers can tell which release we run.
+pub(crate) fn agent_version() -> String {
+    format!("boxpeer/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Whether a stream protocol is one of the Kademlia versions, ours or the
+/// legacy one.
+pub(crate) fn is_kad_protocol(protocol: &str) -> bool {
+    protocol == LEGACY_PROTOCOL
+        || (protocol.starts_with(PROTOCOL_PREFIX) && protocol.contains("/kad/"))
+}
+
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = version.split('.').map(|part| part.parse().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_kad_protocol_is_offered_last() {
+        let network = Network {
+            psk: None,
+            legacy: true,
+        };
+        let protocols = network.kad_protocols();
+        assert_eq!(protocols[0].as_ref(), "/boxpeer/kad/1.0.0");
+        assert_eq!(protocols.last().unwrap().as_ref(), LEGACY_PROTOCOL);
+    }
+
+    #[test]
+    fn legacy_protocol_is_opt_in() {
+        let network = Network {
+            psk: None,
+            legacy: false,
+        };
+        assert_eq!(network.kad_protocols().len(), KAD_PROTOCOL_VERSIONS.len());
+        assert!(!network.is_compatible(LEGACY_PROTOCOL));
+    }
+
+    #[test]
+    fn compatibility_window() {
+        let network = Network {
+            psk: None,
+            legacy: true,
+        };
+        assert!(network.is_compatible("/boxpeer/1.0.0"));
+        assert!(network.is_compatible("/boxpeer/1.4.2"));
+        assert!(network.is_compatible(LEGACY_PROTOCOL));
+        assert!(!network.is_compatible("/boxpeer/2.0.0"));
+        assert!(!network.is_compatible("/ipfs/id/1.0.0"));
+    }
+}
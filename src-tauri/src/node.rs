@@ -1,14 +1,18 @@
 use libp2p::identity;
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::PathBuf;
-use tauri::api::path::cache_dir;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tauri::api::path::{cache_dir, data_dir};
+use tracing::warn;
 
-#[derive(Serialize, Deserialize, Clone)]
+const KEYPAIR_FILE: &str = "peer_keypair.bin";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum NodeType {
     Provider,
     Distributor,
+    #[default]
     Consumer,
 }
 
@@ -17,38 +21,103 @@ struct NodeInfo {
     node_type: NodeType,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PeerInfo {
     pub peer_id: String,
-    pub listening_addr: String,
+    pub addresses: Vec<String>,
+    pub node_type: Option<NodeType>,
+    pub score: f64,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub enum ConnectionDirection {
+    Outbound,
+    Inbound,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionDetails {
+    pub address: String,
+    pub transport: String,
+    pub direction: ConnectionDirection,
+    pub uptime_secs: u64,
+}
+
+/// Everything we know about a connected peer. Identify fields stay empty
+/// until the peer has answered identify.
+#[derive(Serialize)]
+pub struct PeerDetails {
+    pub peer_id: String,
+    pub listen_addrs: Vec<String>,
+    // Our address as the peer sees it.
+    pub observed_addr: Option<String>,
+    pub protocols: Vec<String>,
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub rtt_ms: Option<f64>,
+    pub connections: Vec<ConnectionDetails>,
     pub node_type: Option<NodeType>,
+    pub score: f64,
 }
 
-pub(crate) fn load_or_generate_keypair() -> identity::Keypair {
-    let cache_path = cache_dir().unwrap();
-    let mut file_path = PathBuf::from(cache_path);
-    file_path.push("Boxpeer");
-    file_path.push("peer_keypair.bin".to_string());
+/// Loads the node keypair, creating one on first start. The key that
+/// encrypts stored blocks is derived from it, so with `encrypt_at_rest` on a
+/// keypair that can't be read is an error rather than a reason to make a new
+/// one.
+pub(crate) fn load_or_generate_keypair(encrypt_at_rest: bool) -> io::Result<identity::Keypair> {
+    // `data_dir` is never the cache directory the database lives in: it's
+    // ~/.local/share, Application Support or the roaming AppData depending
+    // on the OS.
+    let mut dir = data_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No data directory found"))?;
+    dir.push("Boxpeer");
+    fs::create_dir_all(&dir)?;
+    let file_path = dir.join(KEYPAIR_FILE);
+
+    let contents = match fs::read(&file_path) {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if let Some(contents) = contents {
+        match identity::Keypair::from_protobuf_encoding(&contents) {
+            Ok(keypair) => return Ok(keypair),
+            Err(e) if encrypt_at_rest => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Keypair at {:?} is invalid and stored blocks are encrypted with it: {}",
+                        file_path, e
+                    ),
+                ))
+            }
+            Err(e) => {
+                warn!("Stored keypair is invalid, generating a new one: {:?}", e);
+                fs::rename(&file_path, file_path.with_extension("bin.invalid"))?;
+            }
+        }
+    }
 
-    // if let Ok(mut file) = File::open(&file_path) {
-    //     let mut contents = Vec::new();
-    //     let _ = file.read_to_end(&mut contents);
-    //     println!("In If");
-    //     identity::Keypair::from_protobuf_encoding(&contents).unwrap()
-    // } else {
-    // Generate a new keypair if no file is found
     let keypair = identity::Keypair::generate_ed25519();
-    println!("In else");
-    // Save the keypair to disk
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(&file_path)
-        .unwrap();
-    let encoded = keypair.to_protobuf_encoding().unwrap();
-    let _ = file.write_all(&encoded);
-    keypair
-    //}
+    write_keypair(&file_path, &keypair)?;
+    Ok(keypair)
+}
+
+/// Writes the keypair through a temporary file so a crash never leaves a
+/// truncated key behind.
+fn write_keypair(path: &Path, keypair: &identity::Keypair) -> io::Result<()> {
+    let encoded = keypair
+        .to_protobuf_encoding()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let tmp_path = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(&encoded)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
 }
 
 pub async fn boxpeer_dir() -> Result<String, String> {
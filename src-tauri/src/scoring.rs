@@ -0,0 +1,300 @@
+use crate::storage::unix_now;
+use libp2p::PeerId;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Bans by peer ID: when the ban lifts as Unix seconds BE, 0 for never,
+// followed by the reason.
+const BANS_TREE: &str = "BOXPEER.BANS";
+
+// Peers scoring below this aren't asked for blocks.
+const SHUN_THRESHOLD: f64 = 50.0;
+// Peers scoring below this are disconnected and banned for a while.
+const BAN_THRESHOLD: f64 = 20.0;
+// Don't judge a peer's success rate on fewer attempts than this.
+const MIN_ATTEMPTS: u32 = 5;
+// Weight of the newest sample in the latency moving average.
+const LATENCY_SMOOTHING: f64 = 0.3;
+const INVALID_PENALTY: f64 = 25.0;
+pub(crate) const AUTO_BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+pub(crate) const BAN_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct PeerScore {
+    successes: u32,
+    failures: u32,
+    invalid: u32,
+    latency: Option<Duration>,
+}
+
+impl PeerScore {
+    /// Success rate out of 100, minus penalties for bad data and slow
+    /// responses. Peers we know nothing about start at 100.
+    fn score(&self) -> f64 {
+        let attempts = self.successes + self.failures;
+        let success_rate = if attempts == 0 {
+            1.0
+        } else {
+            self.successes as f64 / attempts as f64
+        };
+        let latency_penalty = self
+            .latency
+            .map(|latency| latency.as_secs_f64().min(5.0) * 4.0)
+            .unwrap_or_default();
+        success_rate * 100.0 - self.invalid as f64 * INVALID_PENALTY - latency_penalty
+    }
+
+    fn is_judged(&self) -> bool {
+        self.invalid > 0 || self.successes + self.failures >= MIN_ATTEMPTS
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct BannedPeer {
+    pub peer_id: String,
+    pub reason: String,
+    // Unix time the ban lifts, or `None` for bans made by hand.
+    pub until: Option<u64>,
+}
+
+struct Ban {
+    reason: String,
+    expires: Option<(Instant, u64)>,
+}
+
+/// Tracks how well each peer has behaved and which peers are banned. Bans
+/// are kept across restarts; scores start over.
+pub(crate) struct PeerScores {
+    scores: HashMap<PeerId, PeerScore>,
+    bans: HashMap<PeerId, Ban>,
+    tree: sled::Tree,
+}
+
+impl PeerScores {
+    /// Loads the bans saved last session, dropping any that ran out since.
+    pub(crate) fn open(db: &sled::Db) -> sled::Result<Self> {
+        let tree = db.open_tree(BANS_TREE)?;
+        let now = unix_now();
+        let mut bans = HashMap::new();
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            let (Ok(peer), Some((until, reason))) =
+                (PeerId::from_bytes(&key), value.split_first_chunk::<8>())
+            else {
+                tree.remove(key)?;
+                continue;
+            };
+            let expires = match u64::from_be_bytes(*until) {
+                0 => None,
+                until if until <= now => {
+                    tree.remove(key)?;
+                    continue;
+                }
+                until => Some((Instant::now() + Duration::from_secs(until - now), until)),
+            };
+            let reason = String::from_utf8_lossy(reason).into_owned();
+            bans.insert(peer, Ban { reason, expires });
+        }
+        Ok(Self {
+            scores: HashMap::new(),
+            bans,
+            tree,
+        })
+    }
+
+    pub(crate) fn record_success(&mut self, peer: PeerId) {
+        self.scores.entry(peer).or_default().successes += 1;
+    }
+
+    pub(crate) fn record_failure(&mut self, peer: PeerId) {
+        self.scores.entry(peer).or_default().failures += 1;
+    }
+
+    pub(crate) fn record_invalid(&mut self, peer: PeerId) {
+        self.scores.entry(peer).or_default().invalid += 1;
+    }
+
+    pub(crate) fn record_latency(&mut self, peer: PeerId, sample: Duration) {
+        let score = self.scores.entry(peer).or_default();
+        score.latency = Some(match score.latency {
+            Some(latency) => {
+                latency.mul_f64(1.0 - LATENCY_SMOOTHING) + sample.mul_f64(LATENCY_SMOOTHING)
+            }
+            None => sample,
+        });
+    }
+
+    pub(crate) fn score(&self, peer: &PeerId) -> f64 {
+        self.scores.get(peer).map(PeerScore::score).unwrap_or(100.0)
+    }
+
+    /// Whether we've seen enough of `peer` to know it's below the threshold.
+    pub(crate) fn should_ban(&self, peer: &PeerId) -> bool {
+        self.scores
+            .get(peer)
+            .is_some_and(|score| score.is_judged() && score.score() < BAN_THRESHOLD)
+    }
+
+    /// Whether `peer` has done badly enough that we'd rather not ask it for
+    /// blocks.
+    pub(crate) fn should_shun(&self, peer: &PeerId) -> bool {
+        self.scores
+            .get(peer)
+            .is_some_and(|score| score.is_judged() && score.score() < SHUN_THRESHOLD)
+    }
+
+    /// Orders `peers` best first, leaving out banned ones.
+    pub(crate) fn rank(&self, peers: impl Iterator<Item = PeerId>) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = peers.filter(|peer| !self.is_banned(peer)).collect();
+        peers.sort_by(|a, b| self.score(b).total_cmp(&self.score(a)));
+        peers
+    }
+
+    pub(crate) fn ban(
+        &mut self,
+        peer: PeerId,
+        reason: String,
+        duration: Option<Duration>,
+    ) -> sled::Result<()> {
+        let expires =
+            duration.map(|duration| (Instant::now() + duration, unix_now() + duration.as_secs()));
+        let until = expires.map(|(_, unix)| unix).unwrap_or_default();
+        let mut value = until.to_be_bytes().to_vec();
+        value.extend_from_slice(reason.as_bytes());
+        self.tree.insert(peer.to_bytes(), value)?;
+        self.bans.insert(peer, Ban { reason, expires });
+        // Whatever got the peer banned is forgotten once the ban lifts.
+        self.scores.remove(&peer);
+        Ok(())
+    }
+
+    pub(crate) fn unban(&mut self, peer: &PeerId) -> sled::Result<bool> {
+        self.tree.remove(peer.to_bytes())?;
+        Ok(self.bans.remove(peer).is_some())
+    }
+
+    pub(crate) fn banned_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.bans.keys()
+    }
+
+    pub(crate) fn is_banned(&self, peer: &PeerId) -> bool {
+        self.bans.contains_key(peer)
+    }
+
+    /// Removes bans whose time is up and returns the peers they covered.
+    pub(crate) fn take_expired(&mut self) -> sled::Result<Vec<PeerId>> {
+        let now = Instant::now();
+        let expired: Vec<PeerId> = self
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.expires.is_some_and(|(at, _)| at <= now))
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &expired {
+            self.bans.remove(peer);
+            self.tree.remove(peer.to_bytes())?;
+        }
+        Ok(expired)
+    }
+
+    pub(crate) fn banned(&self) -> Vec<BannedPeer> {
+        self.bans
+            .iter()
+            .map(|(peer, ban)| BannedPeer {
+                peer_id: peer.to_string(),
+                reason: ban.reason.clone(),
+                until: ban.expires.map(|(_, unix)| unix),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> (sled::Db, PeerScores) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let scores = PeerScores::open(&db).unwrap();
+        (db, scores)
+    }
+
+    #[test]
+    fn few_failures_arent_judged() {
+        let (_db, mut scores) = open();
+        let peer = PeerId::random();
+        for _ in 0..MIN_ATTEMPTS - 1 {
+            scores.record_failure(peer);
+        }
+        assert_eq!(scores.score(&peer), 0.0);
+        assert!(!scores.should_ban(&peer));
+        scores.record_failure(peer);
+        assert!(scores.should_ban(&peer));
+    }
+
+    #[test]
+    fn invalid_data_is_judged_at_once() {
+        let (_db, mut scores) = open();
+        let peer = PeerId::random();
+        for _ in 0..10 {
+            scores.record_success(peer);
+        }
+        for _ in 0..3 {
+            scores.record_invalid(peer);
+        }
+        assert!(scores.should_shun(&peer));
+        assert!(!scores.should_ban(&peer));
+        scores.record_invalid(peer);
+        assert!(scores.should_ban(&peer));
+    }
+
+    #[test]
+    fn slow_peers_rank_lower() {
+        let (_db, mut scores) = open();
+        let (fast, slow, banned) = (PeerId::random(), PeerId::random(), PeerId::random());
+        scores.record_latency(fast, Duration::from_millis(50));
+        scores.record_latency(slow, Duration::from_secs(2));
+        scores.ban(banned, "test".to_string(), None).unwrap();
+        assert_eq!(
+            scores.rank([slow, banned, fast].into_iter()),
+            vec![fast, slow]
+        );
+    }
+
+    #[test]
+    fn bans_survive_a_restart() {
+        let (db, mut scores) = open();
+        let (manual, timed, lapsed) = (PeerId::random(), PeerId::random(), PeerId::random());
+        scores.ban(manual, "by hand".to_string(), None).unwrap();
+        scores
+            .ban(timed, "bad blocks".to_string(), Some(AUTO_BAN_DURATION))
+            .unwrap();
+        scores
+            .ban(lapsed, "bad blocks".to_string(), Some(Duration::ZERO))
+            .unwrap();
+
+        let reopened = PeerScores::open(&db).unwrap();
+        assert!(reopened.is_banned(&manual));
+        assert!(reopened.is_banned(&timed));
+        assert!(!reopened.is_banned(&lapsed));
+        let mut banned = reopened.banned();
+        banned.sort_by_key(|ban| ban.until);
+        assert_eq!(banned[0].reason, "by hand");
+        assert_eq!(banned[0].until, None);
+        assert!(banned[1].until.is_some());
+    }
+
+    #[test]
+    fn unban_and_expiry_are_saved() {
+        let (db, mut scores) = open();
+        let (manual, lapsed) = (PeerId::random(), PeerId::random());
+        scores.ban(manual, "by hand".to_string(), None).unwrap();
+        scores
+            .ban(lapsed, "bad blocks".to_string(), Some(Duration::ZERO))
+            .unwrap();
+        assert!(scores.unban(&manual).unwrap());
+        assert_eq!(scores.take_expired().unwrap(), vec![lapsed]);
+        assert!(db.open_tree(BANS_TREE).unwrap().is_empty());
+    }
+}
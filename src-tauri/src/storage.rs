@@ -0,0 +1,628 @@
+use crate::car;
+use crate::net::Command;
+use crate::store::NodeBlockstore;
+use anyhow::{anyhow, Result};
+use blockstore::Blockstore;
+use cid::Cid;
+use futures::channel::{mpsc, oneshot};
+use futures::SinkExt;
+use multihash_codetable::{Code, MultihashDigest};
+use serde::Serialize;
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{info, warn};
+
+// Corrupted blocks are moved here so they can be inspected later.
+const QUARANTINE_TREE: &[u8] = b"BOXPEER.QUARANTINE";
+// Running block/byte counters, so stats don't need a full scan. Each
+// backend gets its own tree named after this one.
+const STATS_TREE: &str = "BOXPEER.STATS";
+// Roots the user uploaded, locked or imported, with the size of their DAG.
+const PINS_TREE: &[u8] = b"BOXPEER.PINS";
+// Blocks held as a cache rather than pinned, with when each was last stored
+// or asked for and its size.
+const CACHE_TREE: &[u8] = b"BOXPEER.CACHE";
+
+const BLOCKS_KEY: &[u8] = b"blocks";
+const BYTES_KEY: &[u8] = b"bytes";
+const DEDUP_BYTES_KEY: &[u8] = b"dedup_bytes";
+// Present while the store is closed cleanly, for backends whose writes the
+// counters can't be updated atomically with.
+const CLEAN_KEY: &[u8] = b"clean";
+
+const SCRUB_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const REFETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Default)]
+pub struct ScrubReport {
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub blocks_checked: u64,
+    pub bytes_checked: u64,
+    pub corrupted: Vec<String>,
+    pub repaired: Vec<String>,
+    pub unrepaired: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct StorageHealth {
+    pub quarantined_blocks: usize,
+    pub scrub_running: bool,
+    pub last_scrub: Option<ScrubReport>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PinnedRoot {
+    pub cid: String,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct StorageStats {
+    pub total_blocks: u64,
+    pub total_bytes: u64,
+    pub pinned: Vec<PinnedRoot>,
+    // Bytes we didn't have to store again because the block was already present.
+    pub dedup_savings_bytes: u64,
+    pub quota_bytes: Option<u64>,
+    pub free_bytes: Option<u64>,
+}
+
+/// Block and byte totals after a put or remove, written alongside the block
+/// itself so the two can't drift apart.
+#[derive(Clone, Copy)]
+pub(crate) struct Totals {
+    blocks: u64,
+    bytes: u64,
+}
+
+impl Totals {
+    pub(crate) fn write(
+        &self,
+        tree: &TransactionalTree,
+    ) -> Result<(), UnabortableTransactionError> {
+        tree.insert(BLOCKS_KEY, &self.blocks.to_be_bytes()[..])?;
+        tree.insert(BYTES_KEY, &self.bytes.to_be_bytes()[..])?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct StorageCounters {
+    blocks: AtomicU64,
+    bytes: AtomicU64,
+    dedup_bytes: AtomicU64,
+    initialized: AtomicBool,
+    // `None` for stores that don't persist, like the in-memory one.
+    tree: Option<sled::Tree>,
+}
+
+impl StorageCounters {
+    /// Loads the counters of one backend. `backend` tells stores apart so
+    /// switching backends doesn't carry over the previous one's totals.
+    pub(crate) fn load(db: &sled::Db, backend: &str) -> sled::Result<Self> {
+        let tree = db.open_tree(format!("{}.{}", STATS_TREE, backend))?;
+        let read = |key: &[u8]| -> sled::Result<u64> {
+            Ok(tree
+                .get(key)?
+                .and_then(|value| value.as_ref().try_into().ok())
+                .map(u64::from_be_bytes)
+                .unwrap_or_default())
+        };
+        Ok(Self {
+            blocks: AtomicU64::new(read(BLOCKS_KEY)?),
+            bytes: AtomicU64::new(read(BYTES_KEY)?),
+            dedup_bytes: AtomicU64::new(read(DEDUP_BYTES_KEY)?),
+            initialized: AtomicBool::new(tree.contains_key(BLOCKS_KEY)?),
+            tree: Some(tree),
+        })
+    }
+
+    pub(crate) fn tree(&self) -> Option<&sled::Tree> {
+        self.tree.as_ref()
+    }
+
+    pub(crate) fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn seed(&self, blocks: u64, bytes: u64) -> sled::Result<()> {
+        self.initialized.store(true, Ordering::Relaxed);
+        self.save(Totals { blocks, bytes })
+    }
+
+    /// Whether the last session ended with `mark_clean`. The marker is
+    /// cleared so a crash during this session shows up on the next start.
+    pub(crate) fn take_clean_marker(&self) -> sled::Result<bool> {
+        let Some(tree) = &self.tree else {
+            return Ok(true);
+        };
+        let clean = tree.remove(CLEAN_KEY)?.is_some();
+        tree.flush()?;
+        Ok(clean)
+    }
+
+    pub(crate) fn mark_clean(&self) -> sled::Result<()> {
+        if let Some(tree) = &self.tree {
+            tree.insert(CLEAN_KEY, &[])?;
+            tree.flush()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn after_put(&self, len: u64) -> Totals {
+        Totals {
+            blocks: self.total_blocks() + 1,
+            bytes: self.total_bytes() + len,
+        }
+    }
+
+    pub(crate) fn after_remove(&self, len: u64) -> Totals {
+        Totals {
+            blocks: self.total_blocks().saturating_sub(1),
+            bytes: self.total_bytes().saturating_sub(len),
+        }
+    }
+
+    /// Takes on totals that have already been written to the tree.
+    pub(crate) fn apply(&self, totals: Totals) {
+        self.blocks.store(totals.blocks, Ordering::Relaxed);
+        self.bytes.store(totals.bytes, Ordering::Relaxed);
+    }
+
+    /// Writes `totals` to the tree and takes them on.
+    pub(crate) fn save(&self, totals: Totals) -> sled::Result<()> {
+        if let Some(tree) = &self.tree {
+            tree.insert(BLOCKS_KEY, &totals.blocks.to_be_bytes()[..])?;
+            tree.insert(BYTES_KEY, &totals.bytes.to_be_bytes()[..])?;
+        }
+        self.apply(totals);
+        Ok(())
+    }
+
+    pub(crate) fn record_duplicate(&self, len: u64) -> sled::Result<()> {
+        let dedup_bytes = self.dedup_bytes.fetch_add(len, Ordering::Relaxed) + len;
+        if let Some(tree) = &self.tree {
+            tree.insert(DEDUP_BYTES_KEY, &dedup_bytes.to_be_bytes()[..])?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn total_blocks(&self) -> u64 {
+        self.blocks.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn total_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn dedup_bytes(&self) -> u64 {
+        self.dedup_bytes.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Pins {
+    tree: sled::Tree,
+}
+
+impl Pins {
+    pub(crate) fn open(db: &sled::Db) -> sled::Result<Self> {
+        Ok(Self {
+            tree: db.open_tree(PINS_TREE)?,
+        })
+    }
+
+    /// Records `root` as pinned along with the total size of its DAG.
+    pub(crate) async fn pin(&self, blockstore: &NodeBlockstore, root: Cid) -> Result<()> {
+        let bytes = dag_size(blockstore, root).await?;
+        self.tree
+            .insert(root.to_bytes(), &bytes.to_be_bytes()[..])?;
+        Ok(())
+    }
+
+    pub(crate) fn list(&self) -> Result<Vec<PinnedRoot>> {
+        self.tree
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let cid = Cid::try_from(key.as_ref())?;
+                let bytes = value
+                    .as_ref()
+                    .try_into()
+                    .map(u64::from_be_bytes)
+                    .unwrap_or_default();
+                Ok(PinnedRoot {
+                    cid: cid.to_string(),
+                    bytes,
+                })
+            })
+            .collect()
+    }
+
+    /// Every block reachable from a pinned root.
+    pub(crate) async fn pinned_blocks(&self, blockstore: &NodeBlockstore) -> Result<HashSet<Cid>> {
+        let mut seen = HashSet::new();
+        for entry in self.tree.iter() {
+            let (key, _) = entry?;
+            walk_dag(blockstore, Cid::try_from(key.as_ref())?, &mut seen).await?;
+        }
+        Ok(seen)
+    }
+}
+
+/// Blocks we hold for others or fetched in passing. When room is needed
+/// the least recently used go first; pinned blocks are never evicted, even
+/// if they were cached before being pinned.
+#[derive(Clone)]
+pub(crate) struct Cache {
+    tree: sled::Tree,
+    blockstore: Arc<NodeBlockstore>,
+    pins: Pins,
+}
+
+impl Cache {
+    pub(crate) fn open(
+        db: &sled::Db,
+        blockstore: Arc<NodeBlockstore>,
+        pins: Pins,
+    ) -> sled::Result<Self> {
+        Ok(Self {
+            tree: db.open_tree(CACHE_TREE)?,
+            blockstore,
+            pins,
+        })
+    }
+
+    /// Records `cid` as cached, or as just used if it already was.
+    pub(crate) fn touch(&self, cid: &Cid, bytes: u64) -> sled::Result<()> {
+        let mut value = unix_now().to_be_bytes().to_vec();
+        value.extend_from_slice(&bytes.to_be_bytes());
+        self.tree.insert(cid.to_bytes(), value)?;
+        Ok(())
+    }
+
+    /// Cached blocks, least recently used first, with their sizes.
+    fn entries(&self) -> Result<Vec<(Cid, u64)>> {
+        let mut entries = self
+            .tree
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let read = |range: std::ops::Range<usize>| {
+                    value
+                        .get(range)
+                        .and_then(|b| b.try_into().ok())
+                        .map(u64::from_be_bytes)
+                        .unwrap_or_default()
+                };
+                Ok((read(0..8), Cid::try_from(key.as_ref())?, read(8..16)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|(last_used, ..)| *last_used);
+        Ok(entries
+            .into_iter()
+            .map(|(_, cid, bytes)| (cid, bytes))
+            .collect())
+    }
+
+    /// Evicts cached blocks until `needed` more bytes fit under the quota.
+    /// Returns the evicted CIDs, or `None` without evicting anything when
+    /// even the whole cache wouldn't make enough room.
+    pub(crate) async fn make_room(&self, needed: u64) -> Result<Option<Vec<Cid>>> {
+        let Some(quota) = self.blockstore.quota() else {
+            return Ok(Some(Vec::new()));
+        };
+        let used = self.blockstore.counters().total_bytes();
+        let Some(mut to_free) = (used + needed).checked_sub(quota).filter(|n| *n > 0) else {
+            return Ok(Some(Vec::new()));
+        };
+
+        let pinned = self.pins.pinned_blocks(&self.blockstore).await?;
+        let candidates: Vec<(Cid, u64)> = self
+            .entries()?
+            .into_iter()
+            .filter(|(cid, _)| !pinned.contains(cid))
+            .collect();
+        if candidates.iter().map(|(_, bytes)| bytes).sum::<u64>() < to_free {
+            return Ok(None);
+        }
+
+        let mut evicted = Vec::new();
+        for (cid, bytes) in candidates {
+            if to_free == 0 {
+                break;
+            }
+            self.blockstore
+                .remove(&cid)
+                .await
+                .map_err(|e| anyhow!("Failed to evict {}: {:?}", cid, e))?;
+            self.tree.remove(cid.to_bytes())?;
+            info!("Evicted cached block {} ({} bytes)", cid, bytes);
+            to_free = to_free.saturating_sub(bytes);
+            evicted.push(cid);
+        }
+        Ok(Some(evicted))
+    }
+}
+
+/// Adds up the size of every locally stored block reachable from `root`.
+async fn dag_size(blockstore: &NodeBlockstore, root: Cid) -> Result<u64> {
+    walk_dag(blockstore, root, &mut HashSet::new()).await
+}
+
+/// Visits the DAG under `root`, skipping blocks already in `seen`, and
+/// returns the size of the locally stored blocks it found.
+async fn walk_dag(blockstore: &NodeBlockstore, root: Cid, seen: &mut HashSet<Cid>) -> Result<u64> {
+    let mut total = 0;
+    let mut stack = vec![root];
+    while let Some(cid) = stack.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        if let Some(data) = blockstore
+            .get(&cid)
+            .await
+            .map_err(|e| anyhow!("Failed to read block {}: {:?}", cid, e))?
+        {
+            total += data.len() as u64;
+            stack.extend(car::links(&cid, &data)?);
+        }
+    }
+    Ok(total)
+}
+
+pub(crate) fn storage_stats(blockstore: &NodeBlockstore, pins: &Pins) -> Result<StorageStats> {
+    let counters = blockstore.counters();
+    let quota_bytes = blockstore.quota();
+    let total_bytes = counters.total_bytes();
+    Ok(StorageStats {
+        total_blocks: counters.total_blocks(),
+        total_bytes,
+        pinned: pins.list()?,
+        dedup_savings_bytes: counters.dedup_bytes(),
+        quota_bytes,
+        free_bytes: quota_bytes.map(|quota| quota.saturating_sub(total_bytes)),
+    })
+}
+
+#[derive(Clone)]
+pub(crate) struct Scrubber {
+    blockstore: Arc<NodeBlockstore>,
+    db: sled::Db,
+    command_sender: mpsc::Sender<Command>,
+    last_report: Arc<AsyncMutex<Option<ScrubReport>>>,
+    running: Arc<AsyncMutex<()>>,
+}
+
+impl Scrubber {
+    pub(crate) fn new(
+        blockstore: Arc<NodeBlockstore>,
+        db: sled::Db,
+        command_sender: mpsc::Sender<Command>,
+    ) -> Self {
+        Self {
+            blockstore,
+            db,
+            command_sender,
+            last_report: Default::default(),
+            running: Default::default(),
+        }
+    }
+
+    /// Runs a scrub every `SCRUB_INTERVAL` for as long as the app is alive.
+    pub(crate) fn spawn_periodic(&self) {
+        let scrubber = self.clone();
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + SCRUB_INTERVAL;
+            let mut interval = tokio::time::interval_at(start, SCRUB_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = scrubber.scrub().await {
+                    warn!("Storage scrub failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    pub(crate) async fn health(&self) -> Result<StorageHealth> {
+        let quarantine = self.db.open_tree(QUARANTINE_TREE)?;
+        Ok(StorageHealth {
+            quarantined_blocks: quarantine.len(),
+            scrub_running: self.running.try_lock().is_err(),
+            last_scrub: self.last_report.lock().await.clone(),
+        })
+    }
+
+    /// Checks every stored block against its CID, quarantines the ones whose
+    /// bytes no longer match and tries to fetch a good copy from peers.
+    pub(crate) async fn scrub(&self) -> Result<ScrubReport> {
+        let _guard = self
+            .running
+            .try_lock()
+            .map_err(|_| anyhow!("A storage scrub is already running"))?;
+
+        let mut report = ScrubReport {
+            started_at: unix_now(),
+            ..Default::default()
+        };
+
+        let quarantine = self.db.open_tree(QUARANTINE_TREE)?;
+        let cids = self
+            .blockstore
+            .cids()
+            .await
+            .map_err(|e| anyhow!("Failed to list stored blocks: {:?}", e))?;
+
+        let mut corrupted = Vec::new();
+        for cid in cids {
+            // Nothing we store could have been checked on the way in either.
+            if !hash_supported(&cid) {
+                warn!("Unsupported multihash code for {}, skipping", cid);
+                continue;
+            }
+            let data = match self.blockstore.get(&cid).await {
+                Ok(Some(data)) => data,
+                // The block may have been removed since we listed it.
+                Ok(None) => continue,
+                // An encrypted block that fails to decrypt has been tampered with.
+                Err(e) => {
+                    warn!("Failed to read block {}: {:?}", cid, e);
+                    report.blocks_checked += 1;
+                    corrupted.push(cid);
+                    continue;
+                }
+            };
+            report.blocks_checked += 1;
+            report.bytes_checked += data.len() as u64;
+            if !block_matches_cid(&cid, &data) {
+                quarantine.insert(cid.to_bytes(), data)?;
+                corrupted.push(cid);
+            }
+        }
+        quarantine.flush_async().await?;
+
+        for cid in corrupted {
+            warn!("Block {} does not match its CID, quarantining", cid);
+            report.corrupted.push(cid.to_string());
+            self.blockstore
+                .remove(&cid)
+                .await
+                .map_err(|e| anyhow!("Failed to remove corrupted block: {:?}", e))?;
+
+            match self.refetch(cid).await {
+                Ok(()) => report.repaired.push(cid.to_string()),
+                Err(e) => {
+                    warn!("Could not repair block {}: {:?}", cid, e);
+                    report.unrepaired.push(cid.to_string());
+                }
+            }
+        }
+
+        report.finished_at = unix_now();
+        info!(
+            "Storage scrub checked {} blocks, {} corrupted, {} repaired",
+            report.blocks_checked,
+            report.corrupted.len(),
+            report.repaired.len()
+        );
+        *self.last_report.lock().await = Some(report.clone());
+        Ok(report)
+    }
+
+    async fn refetch(&self, cid: Cid) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .clone()
+            .send(Command::RequestFile { cid, sender })
+            .await?;
+
+        let data = tokio::time::timeout(REFETCH_TIMEOUT, receiver)
+            .await
+            .map_err(|_| anyhow!("Timed out fetching {} from peers", cid))???;
+
+        if !block_matches_cid(&cid, &data) {
+            return Err(anyhow!("Peers returned data that does not match {}", cid));
+        }
+
+        // Bitswap stores what it fetches, so this only catches a copy that
+        // came from somewhere else, and doesn't count the block twice.
+        let stored = self
+            .blockstore
+            .has(&cid)
+            .await
+            .map_err(|e| anyhow!("Failed to check repaired block: {:?}", e))?;
+        if !stored {
+            self.blockstore
+                .put_keyed(&cid, &data)
+                .await
+                .map_err(|e| self.blockstore.put_error(e, data.len() as u64))?;
+        }
+
+        let quarantine = self.db.open_tree(QUARANTINE_TREE)?;
+        quarantine.remove(cid.to_bytes())?;
+        Ok(())
+    }
+}
+
+/// Recomputes the multihash of `data` with the same hash function the CID
+/// was created with, the same way `FileBlock::cid` does on upload. Blocks
+/// hashed with a function we don't know can't be verified, so they never match.
+pub(crate) fn block_matches_cid(cid: &Cid, data: &[u8]) -> bool {
+    Code::try_from(cid.hash().code()).is_ok_and(|code| code.digest(data) == *cid.hash())
+}
+
+fn hash_supported(cid: &Cid) -> bool {
+    Code::try_from(cid.hash().code()).is_ok()
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BlockstoreConfig;
+
+    const RAW_CODEC: u64 = 0x55;
+
+    #[test]
+    fn matching_block_matches() {
+        let cid = Cid::new_v1(RAW_CODEC, Code::Sha2_256.digest(b"data"));
+        assert!(block_matches_cid(&cid, b"data"));
+        assert!(!block_matches_cid(&cid, b"other data"));
+    }
+
+    #[tokio::test]
+    async fn eviction_skips_pinned_blocks() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blockstore = Arc::new(
+            NodeBlockstore::open(
+                &BlockstoreConfig::Sled,
+                &db,
+                &std::env::temp_dir(),
+                None,
+                Some(10),
+            )
+            .await
+            .unwrap(),
+        );
+        let pins = Pins::open(&db).unwrap();
+        let cache = Cache::open(&db, blockstore.clone(), pins.clone()).unwrap();
+        let (old, pinned) = (
+            Cid::new_v1(RAW_CODEC, Code::Sha2_256.digest(b"old!")),
+            Cid::new_v1(RAW_CODEC, Code::Sha2_256.digest(b"pin!")),
+        );
+        // `pinned` was used longest ago, so it would go first if it could.
+        for (cid, data, last_used) in [(pinned, b"pin!", 1u64), (old, b"old!", 2)] {
+            blockstore.put_keyed(&cid, data).await.unwrap();
+            let mut value = last_used.to_be_bytes().to_vec();
+            value.extend_from_slice(&4u64.to_be_bytes());
+            cache.tree.insert(cid.to_bytes(), value).unwrap();
+        }
+        pins.pin(&blockstore, pinned).await.unwrap();
+
+        assert_eq!(cache.make_room(8).await.unwrap(), None);
+        assert!(blockstore.has(&old).await.unwrap());
+        assert_eq!(cache.make_room(4).await.unwrap(), Some(vec![old]));
+        assert!(!blockstore.has(&old).await.unwrap());
+        assert!(blockstore.has(&pinned).await.unwrap());
+        assert_eq!(cache.make_room(2).await.unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn unknown_hash_never_matches() {
+        let digest = Code::Sha2_256.digest(b"data");
+        let unknown = cid::multihash::Multihash::<64>::wrap(0x300000, digest.digest()).unwrap();
+        let cid = Cid::new_v1(RAW_CODEC, unknown);
+        assert!(!block_matches_cid(&cid, b"data"));
+    }
+}
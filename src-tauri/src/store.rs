@@ -0,0 +1,884 @@
+use crate::config::BlockstoreConfig;
+use crate::error::BoxPeerError;
+use crate::storage::{StorageCounters, Totals};
+use blockstore::{
+    Blockstore, Error as BlockstoreError, InMemoryBlockstore, Result as BlockstoreResult,
+    SledBlockstore,
+};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use cid::{Cid, CidGeneric};
+use libp2p::identity;
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex as AsyncMutex;
+
+// Tree used by `SledBlockstore` to keep blocks keyed by their CID bytes.
+const SLED_BLOCKS_TREE: &[u8] = b"BLOCKSTORE.BLOCKS";
+
+// Per-backend tree recording whether its blocks are encrypted at rest.
+const FORMAT_TREE: &str = "BOXPEER.FORMAT";
+// Set once every block in the store is sealed.
+const SEALED_KEY: &[u8] = b"sealed";
+// Last CID sealed by an unfinished migration.
+const SEALING_PROGRESS_KEY: &[u8] = b"sealing_progress";
+// A sealed known value, to tell whether we still have the key the blocks
+// were sealed with.
+const KEY_CHECK_KEY: &[u8] = b"key_check";
+const KEY_CHECK_AAD: &[u8] = b"boxpeer key check";
+
+// Version tag in front of every sealed block, followed by the nonce and
+// ciphertext. Whether a block is sealed comes from `FORMAT_TREE`, not this.
+const SEALED_MAGIC: &[u8; 8] = b"BPSEALv1";
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const KEY_DOMAIN: &[u8] = b"boxpeer blockstore encryption at rest";
+
+enum Backend {
+    Sled {
+        store: SledBlockstore,
+        tree: sled::Tree,
+    },
+    Memory(InMemoryBlockstore<64>),
+    FlatFile(FlatFileBlockstore),
+}
+
+/// The blockstore the node runs on, chosen in config. `Blockstore` has
+/// generic methods so it can't be boxed; we dispatch over the backends here.
+/// Block and byte counts are kept up to date on every put and remove so stats
+/// never need a full scan. With a cipher set, blocks are encrypted before they
+/// reach the backend while still being keyed by their plaintext CID.
+pub struct NodeBlockstore {
+    backend: Backend,
+    // Set exactly when the blocks are sealed.
+    cipher: Option<BlockCipher>,
+    // `None` for the in-memory store, which is always empty on open.
+    format: Option<sled::Tree>,
+    counters: StorageCounters,
+    quota: Option<u64>,
+    // Puts and removes check what's stored, write and count as one step.
+    write_lock: AsyncMutex<()>,
+}
+
+impl NodeBlockstore {
+    pub(crate) async fn open(
+        config: &BlockstoreConfig,
+        db: &sled::Db,
+        default_dir: &Path,
+        cipher: Option<BlockCipher>,
+        quota: Option<u64>,
+    ) -> BlockstoreResult<Self> {
+        let backend = match config {
+            BlockstoreConfig::Sled => Backend::Sled {
+                store: SledBlockstore::new(db.clone()).await?,
+                tree: db.open_tree(SLED_BLOCKS_TREE).map_err(db_error)?,
+            },
+            BlockstoreConfig::Memory => Backend::Memory(InMemoryBlockstore::new()),
+            BlockstoreConfig::FlatFile { path } => {
+                let dir = path.clone().unwrap_or_else(|| default_dir.join("blocks"));
+                Backend::FlatFile(FlatFileBlockstore::new(dir).await?)
+            }
+        };
+
+        let id = match &backend {
+            Backend::Memory(_) => None,
+            Backend::Sled { .. } => Some("sled".to_string()),
+            Backend::FlatFile(store) => Some(format!("flatfile:{}", store.dir.display())),
+        };
+        // In-memory blocks don't survive a restart, so neither should their counters.
+        let counters = match &id {
+            Some(id) => StorageCounters::load(db, id).map_err(db_error)?,
+            None => StorageCounters::default(),
+        };
+        let format = id
+            .map(|id| db.open_tree(format!("{}.{}", FORMAT_TREE, id)))
+            .transpose()
+            .map_err(db_error)?;
+        // Flat file writes can't share a transaction with the counters, so
+        // recount after any session that didn't close cleanly.
+        let clean = counters.take_clean_marker().map_err(db_error)?;
+        let recount =
+            !counters.is_initialized() || (matches!(backend, Backend::FlatFile(_)) && !clean);
+
+        let store = NodeBlockstore {
+            backend,
+            cipher,
+            format,
+            counters,
+            quota,
+            write_lock: AsyncMutex::new(()),
+        };
+        store.check_format().await?;
+        if recount {
+            store.seed_counters().await?;
+        }
+        Ok(store)
+    }
+
+    /// Makes sure the blocks are in the form the cipher setting expects,
+    /// sealing any written before encryption at rest was turned on.
+    async fn check_format(&self) -> BlockstoreResult<()> {
+        let Some(format) = &self.format else {
+            return Ok(());
+        };
+        let sealed = format.contains_key(SEALED_KEY).map_err(db_error)?;
+        let sealing = format
+            .contains_key(SEALING_PROGRESS_KEY)
+            .map_err(db_error)?;
+        let Some(cipher) = &self.cipher else {
+            if sealed || sealing {
+                return Err(BlockstoreError::StoredDataError(
+                    "Blocks are encrypted at rest, turn encrypt_at_rest back on to read them"
+                        .into(),
+                ));
+            }
+            return Ok(());
+        };
+        if sealed {
+            let check = format.get(KEY_CHECK_KEY).map_err(db_error)?;
+            let verified = check.is_some_and(|check| cipher.open(KEY_CHECK_AAD, &check).is_ok());
+            if !verified {
+                return Err(BlockstoreError::StoredDataError(
+                    "Blocks were encrypted with a different node key".into(),
+                ));
+            }
+            return Ok(());
+        }
+        self.seal_existing(cipher, format).await
+    }
+
+    /// Encrypts every stored block, picking up where an interrupted run
+    /// stopped. Blocks are visited in CID order and each one is replaced in
+    /// a single write, so at most the last one may already be sealed.
+    async fn seal_existing(
+        &self,
+        cipher: &BlockCipher,
+        format: &sled::Tree,
+    ) -> BlockstoreResult<()> {
+        let progress = format.get(SEALING_PROGRESS_KEY).map_err(db_error)?;
+        let mut cids = self.cids().await?;
+        cids.sort_by_key(|cid| cid.to_bytes());
+        for cid in cids {
+            let key = cid.to_bytes();
+            if progress
+                .as_ref()
+                .is_some_and(|done| key.as_slice() <= done.as_ref())
+            {
+                continue;
+            }
+            let Some(data) = self.backend_get(&cid).await? else {
+                continue;
+            };
+            // Only a block sealed right before a crash can authenticate.
+            if cipher.open(&key, &data).is_err() {
+                let sealed = cipher.seal(&key, &data)?;
+                self.backend_replace(&cid, &sealed).await?;
+            }
+            format.insert(SEALING_PROGRESS_KEY, key).map_err(db_error)?;
+        }
+        let check = cipher.seal(KEY_CHECK_AAD, KEY_CHECK_AAD)?;
+        let mut batch = sled::Batch::default();
+        batch.insert(KEY_CHECK_KEY, check);
+        batch.insert(SEALED_KEY, &[]);
+        batch.remove(SEALING_PROGRESS_KEY);
+        format.apply_batch(batch).map_err(db_error)?;
+        format.flush_async().await.map_err(db_error)?;
+        Ok(())
+    }
+
+    pub(crate) fn counters(&self) -> &StorageCounters {
+        &self.counters
+    }
+
+    pub(crate) fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// What to tell the caller when `needed` more bytes don't fit.
+    pub(crate) fn storage_full(&self, needed: u64) -> BoxPeerError {
+        BoxPeerError::StorageFull {
+            used: self.counters.total_bytes(),
+            quota: self.quota.unwrap_or_default(),
+            needed,
+        }
+    }
+
+    /// Converts an error from storing `needed` bytes, with the sizes filled
+    /// in if the store is full.
+    pub(crate) fn put_error(&self, e: BlockstoreError, needed: u64) -> BoxPeerError {
+        match e {
+            BlockstoreError::ValueTooLarge => self.storage_full(needed),
+            e => e.into(),
+        }
+    }
+
+    /// Writes everything to disk and records that the store was closed
+    /// cleanly, so the counters can be trusted on the next start.
+    pub(crate) async fn flush(&self) -> BlockstoreResult<()> {
+        let _guard = self.write_lock.lock().await;
+        match &self.backend {
+            Backend::Sled { tree, .. } => {
+                tree.flush_async().await.map_err(db_error)?;
+            }
+            Backend::Memory(_) => {}
+            Backend::FlatFile(store) => store.sync().await?,
+        }
+        if let Some(tree) = self.counters.tree() {
+            tree.flush_async().await.map_err(db_error)?;
+        }
+        self.counters.mark_clean().map_err(db_error)
+    }
+
+    /// Counts what's on disk when the stored totals can't be trusted.
+    async fn seed_counters(&self) -> BlockstoreResult<()> {
+        let (mut blocks, mut bytes) = (0, 0);
+        for cid in self.cids().await? {
+            if let Some(data) = self.backend_get(&cid).await? {
+                blocks += 1;
+                bytes += self.plaintext_len(&data);
+            }
+        }
+        self.counters.seed(blocks, bytes).map_err(db_error)
+    }
+
+    /// Writes or deletes a block in the sled tree and stores the new totals
+    /// in the same transaction.
+    async fn sled_write(
+        &self,
+        tree: &sled::Tree,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        totals: Totals,
+    ) -> BlockstoreResult<()> {
+        let blocks = tree.clone();
+        let stats =
+            self.counters.tree().cloned().ok_or_else(|| {
+                BlockstoreError::StoredDataError("Counters aren't persisted".into())
+            })?;
+        tokio::task::spawn_blocking(move || {
+            (&blocks, &stats).transaction(|(blocks, stats)| {
+                match &value {
+                    Some(value) => blocks.insert(key.as_slice(), value.as_slice())?,
+                    None => blocks.remove(key.as_slice())?,
+                };
+                totals.write(stats)?;
+                Ok::<_, ConflictableTransactionError<sled::Error>>(())
+            })
+        })
+        .await
+        .map_err(|e| BlockstoreError::ExecutorError(e.to_string()))?
+        .map_err(|e| match e {
+            TransactionError::Abort(e) | TransactionError::Storage(e) => db_error(e),
+        })?;
+        self.counters.apply(totals);
+        Ok(())
+    }
+
+    /// Lists the CIDs of every block currently stored.
+    pub(crate) async fn cids(&self) -> BlockstoreResult<Vec<Cid>> {
+        match &self.backend {
+            Backend::Sled { tree, .. } => {
+                let tree = tree.clone();
+                tokio::task::spawn_blocking(move || {
+                    tree.iter()
+                        .keys()
+                        .filter_map(|key| match key {
+                            Ok(key) => Cid::try_from(key.as_ref()).ok().map(Ok),
+                            Err(e) => Some(Err(db_error(e))),
+                        })
+                        .collect()
+                })
+                .await
+                .map_err(|e| BlockstoreError::ExecutorError(e.to_string()))?
+            }
+            // `InMemoryBlockstore` can't list its keys. Its blocks never touch
+            // disk, so there is nothing to count, seal or scrub after a restart.
+            Backend::Memory(_) => Ok(Vec::new()),
+            Backend::FlatFile(store) => store.cids().await,
+        }
+    }
+
+    async fn backend_get<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+    ) -> BlockstoreResult<Option<Vec<u8>>> {
+        match &self.backend {
+            Backend::Sled { store, .. } => store.get(cid).await,
+            Backend::Memory(store) => store.get(cid).await,
+            Backend::FlatFile(store) => store.get(cid).await,
+        }
+    }
+
+    async fn backend_put<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+        data: &[u8],
+    ) -> BlockstoreResult<()> {
+        match &self.backend {
+            Backend::Sled { store, .. } => store.put_keyed(cid, data).await,
+            Backend::Memory(store) => store.put_keyed(cid, data).await,
+            Backend::FlatFile(store) => store.put_keyed(cid, data).await,
+        }
+    }
+
+    /// Overwrites a stored block in one step.
+    async fn backend_replace(&self, cid: &Cid, data: &[u8]) -> BlockstoreResult<()> {
+        match &self.backend {
+            Backend::Sled { tree, .. } => {
+                let tree = tree.clone();
+                let (key, data) = (cid.to_bytes(), data.to_vec());
+                tokio::task::spawn_blocking(move || tree.insert(key, data))
+                    .await
+                    .map_err(|e| BlockstoreError::ExecutorError(e.to_string()))?
+                    .map_err(db_error)?;
+                Ok(())
+            }
+            // Nothing is stored yet when the in-memory store is opened.
+            Backend::Memory(_) => Ok(()),
+            Backend::FlatFile(store) => store.write(cid, data).await,
+        }
+    }
+
+    async fn backend_remove<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<()> {
+        match &self.backend {
+            Backend::Sled { store, .. } => store.remove(cid).await,
+            Backend::Memory(store) => store.remove(cid).await,
+            Backend::FlatFile(store) => store.remove(cid).await,
+        }
+    }
+
+    /// Size of the plaintext for a block as it is stored in the backend.
+    fn plaintext_len(&self, stored: &[u8]) -> u64 {
+        match &self.cipher {
+            Some(_) => stored.len().saturating_sub(BlockCipher::OVERHEAD) as u64,
+            None => stored.len() as u64,
+        }
+    }
+}
+
+impl Blockstore for NodeBlockstore {
+    async fn get<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<Option<Vec<u8>>> {
+        let Some(data) = self.backend_get(cid).await? else {
+            return Ok(None);
+        };
+        match &self.cipher {
+            Some(cipher) => cipher.open(&cid.to_bytes(), &data).map(Some),
+            None => Ok(Some(data)),
+        }
+    }
+
+    /// Fails with `ValueTooLarge` when the block would take the store over
+    /// its quota, whoever is storing it, or the disk is full.
+    async fn put_keyed<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+        data: &[u8],
+    ) -> BlockstoreResult<()> {
+        let _guard = self.write_lock.lock().await;
+        let len = data.len() as u64;
+        if self.has(cid).await? {
+            return self.counters.record_duplicate(len).map_err(db_error);
+        }
+        if let Some(quota) = self.quota {
+            if self.counters.total_bytes() + len > quota {
+                return Err(BlockstoreError::ValueTooLarge);
+            }
+        }
+        let stored = match &self.cipher {
+            Some(cipher) => cipher.seal(&cid.to_bytes(), data)?,
+            None => data.to_vec(),
+        };
+        let totals = self.counters.after_put(len);
+        match &self.backend {
+            Backend::Sled { tree, .. } => {
+                self.sled_write(tree, cid.to_bytes(), Some(stored), totals)
+                    .await
+            }
+            _ => {
+                self.backend_put(cid, &stored).await?;
+                self.counters.save(totals).map_err(db_error)
+            }
+        }
+    }
+
+    async fn remove<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<()> {
+        let _guard = self.write_lock.lock().await;
+        let Some(data) = self.backend_get(cid).await? else {
+            return Ok(());
+        };
+        let totals = self.counters.after_remove(self.plaintext_len(&data));
+        match &self.backend {
+            Backend::Sled { tree, .. } => self.sled_write(tree, cid.to_bytes(), None, totals).await,
+            _ => {
+                self.backend_remove(cid).await?;
+                self.counters.save(totals).map_err(db_error)
+            }
+        }
+    }
+
+    async fn has<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<bool> {
+        match &self.backend {
+            Backend::Sled { store, .. } => store.has(cid).await,
+            Backend::Memory(store) => store.has(cid).await,
+            Backend::FlatFile(store) => store.has(cid).await,
+        }
+    }
+
+    async fn close(self) -> BlockstoreResult<()> {
+        match self.backend {
+            Backend::Sled { store, .. } => store.close().await,
+            Backend::Memory(store) => store.close().await,
+            Backend::FlatFile(store) => store.close().await,
+        }
+    }
+}
+
+/// The view of the blockstore handed to bitswap. With serving switched off
+/// every block looks missing to it, so we still fetch blocks from peers but
+/// never hand ours out. Local reads go to `NodeBlockstore` directly.
+pub(crate) struct BitswapStore {
+    inner: Arc<NodeBlockstore>,
+    serve: Arc<AtomicBool>,
+}
+
+impl BitswapStore {
+    pub(crate) fn new(inner: Arc<NodeBlockstore>, serve: Arc<AtomicBool>) -> Self {
+        Self { inner, serve }
+    }
+
+    fn serving(&self) -> bool {
+        self.serve.load(Ordering::Relaxed)
+    }
+}
+
+impl Blockstore for BitswapStore {
+    async fn get<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<Option<Vec<u8>>> {
+        if !self.serving() {
+            return Ok(None);
+        }
+        self.inner.get(cid).await
+    }
+
+    async fn put_keyed<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+        data: &[u8],
+    ) -> BlockstoreResult<()> {
+        self.inner.put_keyed(cid, data).await
+    }
+
+    async fn remove<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<()> {
+        self.inner.remove(cid).await
+    }
+
+    async fn has<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<bool> {
+        if !self.serving() {
+            return Ok(false);
+        }
+        self.inner.has(cid).await
+    }
+
+    // The underlying store is shared and closed by its owner.
+    async fn close(self) -> BlockstoreResult<()> {
+        Ok(())
+    }
+}
+
+/// Encrypts blocks with XChaCha20-Poly1305 under a key derived from the node
+/// keypair. The CID is bound in as associated data so a sealed block can't be
+/// swapped in under another CID.
+pub struct BlockCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl BlockCipher {
+    const OVERHEAD: usize = SEALED_MAGIC.len() + NONCE_LEN + TAG_LEN;
+
+    pub(crate) fn from_keypair(keypair: &identity::Keypair) -> Option<Self> {
+        let key = keypair.derive_secret(KEY_DOMAIN)?;
+        Some(Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        })
+    }
+
+    fn seal(&self, cid: &[u8], data: &[u8]) -> BlockstoreResult<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: cid,
+                },
+            )
+            .map_err(|_| BlockstoreError::StoredDataError("Failed to encrypt block".into()))?;
+
+        let mut sealed = Vec::with_capacity(Self::OVERHEAD + data.len());
+        sealed.extend_from_slice(SEALED_MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, cid: &[u8], sealed: &[u8]) -> BlockstoreResult<Vec<u8>> {
+        if sealed.len() < Self::OVERHEAD || !sealed.starts_with(SEALED_MAGIC) {
+            return Err(BlockstoreError::StoredDataError(
+                "Block is not in the sealed format".into(),
+            ));
+        }
+        let (nonce, ciphertext) = sealed[SEALED_MAGIC.len()..].split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: cid,
+                },
+            )
+            .map_err(|_| BlockstoreError::StoredDataError("Failed to decrypt block".into()))
+    }
+}
+
+/// Stores one file per block in a directory, sharded by the last two
+/// characters of the CID like IPFS' flatfs. Suited to content sets too large
+/// to keep comfortably in a single sled database.
+pub struct FlatFileBlockstore {
+    dir: PathBuf,
+    // Shards with blocks added or removed since the last sync.
+    dirty: Mutex<HashSet<PathBuf>>,
+}
+
+impl FlatFileBlockstore {
+    pub(crate) async fn new(dir: PathBuf) -> BlockstoreResult<Self> {
+        fs::create_dir_all(&dir).await.map_err(io_error)?;
+        Ok(Self {
+            dir,
+            dirty: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn mark_dirty(&self, path: &Path) {
+        if let Some(shard) = path.parent() {
+            self.dirty.lock().unwrap().insert(shard.to_path_buf());
+        }
+    }
+
+    /// Makes the renames and removals since the last sync durable. Block
+    /// contents are synced as they're written.
+    async fn sync(&self) -> BlockstoreResult<()> {
+        let shards: Vec<PathBuf> = self.dirty.lock().unwrap().drain().collect();
+        // Windows can't open a directory to sync it, and NTFS journals
+        // renames anyway.
+        if cfg!(unix) {
+            for dir in shards.iter().chain([&self.dir]) {
+                let dir = fs::File::open(dir).await.map_err(io_error)?;
+                dir.sync_all().await.map_err(io_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn block_path<const S: usize>(&self, cid: &CidGeneric<S>) -> PathBuf {
+        let name = cid.to_string();
+        let shard = &name[name.len().saturating_sub(3)..name.len().saturating_sub(1)];
+        self.dir.join(shard).join(name)
+    }
+
+    /// Writes a block, replacing any existing copy. The data goes to a
+    /// temporary file first so a crash never leaves a truncated block behind
+    /// under a valid CID.
+    async fn write<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+        data: &[u8],
+    ) -> BlockstoreResult<()> {
+        let path = self.block_path(cid);
+        if let Some(shard) = path.parent() {
+            fs::create_dir_all(shard).await.map_err(io_error)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path).await.map_err(io_error)?;
+        file.write_all(data).await.map_err(io_error)?;
+        file.sync_all().await.map_err(io_error)?;
+        fs::rename(&tmp_path, &path).await.map_err(io_error)?;
+        self.mark_dirty(&path);
+        Ok(())
+    }
+
+    async fn cids(&self) -> BlockstoreResult<Vec<Cid>> {
+        let mut cids = Vec::new();
+        let mut shards = fs::read_dir(&self.dir).await.map_err(io_error)?;
+        while let Some(shard) = shards.next_entry().await.map_err(io_error)? {
+            if !shard.file_type().await.map_err(io_error)?.is_dir() {
+                continue;
+            }
+            let mut blocks = fs::read_dir(shard.path()).await.map_err(io_error)?;
+            while let Some(block) = blocks.next_entry().await.map_err(io_error)? {
+                if let Some(cid) = block
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| Cid::try_from(name).ok())
+                {
+                    cids.push(cid);
+                }
+            }
+        }
+        Ok(cids)
+    }
+}
+
+impl Blockstore for FlatFileBlockstore {
+    async fn get<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<Option<Vec<u8>>> {
+        match fs::read(self.block_path(cid)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    async fn put_keyed<const S: usize>(
+        &self,
+        cid: &CidGeneric<S>,
+        data: &[u8],
+    ) -> BlockstoreResult<()> {
+        let path = self.block_path(cid);
+        if fs::try_exists(&path).await.map_err(io_error)? {
+            return Ok(());
+        }
+        self.write(cid, data).await
+    }
+
+    async fn remove<const S: usize>(&self, cid: &CidGeneric<S>) -> BlockstoreResult<()> {
+        let path = self.block_path(cid);
+        match fs::remove_file(&path).await {
+            Ok(()) => {
+                self.mark_dirty(&path);
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    async fn close(self) -> BlockstoreResult<()> {
+        Ok(())
+    }
+}
+
+// A full disk is reported the same way as a full quota.
+fn io_error(e: std::io::Error) -> BlockstoreError {
+    if e.kind() == std::io::ErrorKind::StorageFull {
+        return BlockstoreError::ValueTooLarge;
+    }
+    BlockstoreError::FatalDatabaseError(e.to_string())
+}
+
+fn db_error(e: sled::Error) -> BlockstoreError {
+    match e {
+        sled::Error::Io(e) => io_error(e),
+        e => BlockstoreError::FatalDatabaseError(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multihash_codetable::{Code, MultihashDigest};
+
+    fn block(data: &[u8]) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(data))
+    }
+
+    async fn sled_store(db: &sled::Db, quota: Option<u64>) -> NodeBlockstore {
+        NodeBlockstore::open(
+            &BlockstoreConfig::Sled,
+            db,
+            &std::env::temp_dir(),
+            None,
+            quota,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn counters_follow_puts_and_removes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = sled_store(&db, None).await;
+        let cid = block(b"hello");
+
+        store.put_keyed(&cid, b"hello").await.unwrap();
+        store.put_keyed(&cid, b"hello").await.unwrap();
+        assert_eq!(store.counters().total_blocks(), 1);
+        assert_eq!(store.counters().total_bytes(), 5);
+        assert_eq!(store.counters().dedup_bytes(), 5);
+
+        store.remove(&cid).await.unwrap();
+        assert_eq!(store.counters().total_blocks(), 0);
+        assert_eq!(store.counters().total_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn counters_are_kept_per_backend() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = sled_store(&db, None).await;
+        store.put_keyed(&block(b"hello"), b"hello").await.unwrap();
+        drop(store);
+
+        let dir = std::env::temp_dir().join(format!("boxpeer-flatfile-{}", std::process::id()));
+        let config = BlockstoreConfig::FlatFile {
+            path: Some(dir.clone()),
+        };
+        let flat = NodeBlockstore::open(&config, &db, &dir, None, None)
+            .await
+            .unwrap();
+        assert_eq!(flat.counters().total_blocks(), 0);
+        drop(flat);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let store = sled_store(&db, None).await;
+        assert_eq!(store.counters().total_blocks(), 1);
+    }
+
+    #[tokio::test]
+    async fn flat_file_flush_syncs_and_marks_clean() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let dir = std::env::temp_dir().join(format!("boxpeer-flush-{}", std::process::id()));
+        let config = BlockstoreConfig::FlatFile {
+            path: Some(dir.clone()),
+        };
+        let store = NodeBlockstore::open(&config, &db, &dir, None, None)
+            .await
+            .unwrap();
+        store.put_keyed(&block(b"kept"), b"kept").await.unwrap();
+        store.put_keyed(&block(b"gone"), b"gone").await.unwrap();
+        store.remove(&block(b"gone")).await.unwrap();
+        store.flush().await.unwrap();
+        let Backend::FlatFile(flat) = &store.backend else {
+            unreachable!()
+        };
+        assert!(flat.dirty.lock().unwrap().is_empty());
+        drop(store);
+
+        let store = NodeBlockstore::open(&config, &db, &dir, None, None)
+            .await
+            .unwrap();
+        assert_eq!(store.counters().total_blocks(), 1);
+        assert!(store.has(&block(b"kept")).await.unwrap());
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn puts_past_the_quota_fail() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = sled_store(&db, Some(8)).await;
+
+        store.put_keyed(&block(b"hello"), b"hello").await.unwrap();
+        let result = store.put_keyed(&block(b"world"), b"world").await;
+        assert!(matches!(result, Err(BlockstoreError::ValueTooLarge)));
+        assert_eq!(store.counters().total_blocks(), 1);
+        assert!(matches!(
+            store.put_error(result.unwrap_err(), 5),
+            BoxPeerError::StorageFull {
+                used: 5,
+                quota: 8,
+                needed: 5
+            }
+        ));
+    }
+
+    #[test]
+    fn full_disk_is_reported_like_a_full_quota() {
+        let e = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        assert!(matches!(io_error(e), BlockstoreError::ValueTooLarge));
+    }
+
+    fn cipher(keypair: &identity::Keypair) -> Option<BlockCipher> {
+        BlockCipher::from_keypair(keypair)
+    }
+
+    async fn open_sled(
+        db: &sled::Db,
+        cipher: Option<BlockCipher>,
+    ) -> BlockstoreResult<NodeBlockstore> {
+        NodeBlockstore::open(
+            &BlockstoreConfig::Sled,
+            db,
+            &std::env::temp_dir(),
+            cipher,
+            None,
+        )
+        .await
+    }
+
+    #[test]
+    fn sealed_blocks_are_bound_to_their_cid() {
+        let cipher = cipher(&identity::Keypair::generate_ed25519()).unwrap();
+        let sealed = cipher.seal(b"cid", b"data").unwrap();
+        assert_eq!(cipher.open(b"cid", &sealed).unwrap(), b"data");
+        assert!(cipher.open(b"other cid", &sealed).is_err());
+        assert!(cipher
+            .open(b"cid", b"BPSEALv1 but not really sealed")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn existing_blocks_are_sealed_once() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let keypair = identity::Keypair::generate_ed25519();
+        let cid = block(b"hello");
+
+        let plain = open_sled(&db, None).await.unwrap();
+        plain.put_keyed(&cid, b"hello").await.unwrap();
+        drop(plain);
+
+        let sealed = open_sled(&db, cipher(&keypair)).await.unwrap();
+        assert_ne!(sealed.backend_get(&cid).await.unwrap().unwrap(), b"hello");
+        assert_eq!(sealed.get(&cid).await.unwrap().unwrap(), b"hello");
+        assert_eq!(sealed.counters().total_bytes(), 5);
+        drop(sealed);
+
+        // Reopening doesn't seal the block a second time.
+        let sealed = open_sled(&db, cipher(&keypair)).await.unwrap();
+        assert_eq!(sealed.get(&cid).await.unwrap().unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn interrupted_sealing_resumes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let keypair = identity::Keypair::generate_ed25519();
+        let (first, second) = (block(b"first"), block(b"second"));
+
+        let plain = open_sled(&db, None).await.unwrap();
+        plain.put_keyed(&first, b"first").await.unwrap();
+        plain.put_keyed(&second, b"second").await.unwrap();
+        // Seal one block by hand as if a migration crashed right after it.
+        let cipher_copy = cipher(&keypair).unwrap();
+        let sealed = cipher_copy.seal(&first.to_bytes(), b"first").unwrap();
+        plain.backend_replace(&first, &sealed).await.unwrap();
+        drop(plain);
+
+        let store = open_sled(&db, cipher(&keypair)).await.unwrap();
+        assert_eq!(store.get(&first).await.unwrap().unwrap(), b"first");
+        assert_eq!(store.get(&second).await.unwrap().unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn sealed_store_needs_the_same_key() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let keypair = identity::Keypair::generate_ed25519();
+        drop(open_sled(&db, cipher(&keypair)).await.unwrap());
+
+        assert!(open_sled(&db, None).await.is_err());
+        let other = identity::Keypair::generate_ed25519();
+        assert!(open_sled(&db, cipher(&other)).await.is_err());
+        assert!(open_sled(&db, cipher(&keypair)).await.is_ok());
+    }
+}
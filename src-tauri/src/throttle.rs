@@ -0,0 +1,263 @@
+use crate::config::BandwidthLimits;
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Don't wake a throttled stream for less than this many bytes.
+const MIN_CHUNK: u64 = 4096;
+// Past this many peers, buckets that have filled back up are dropped. A
+// full bucket is the same as a new one, so nothing is lost.
+const MAX_IDLE_PEERS: usize = 256;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    Upload,
+    Download,
+}
+
+/// Holds up to one second's worth of tokens. An unlimited bucket always
+/// has room.
+struct TokenBucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            tokens: rate.unwrap_or_default() as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>) {
+        self.refill(Instant::now());
+        self.rate = rate;
+        if let Some(rate) = rate {
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.rate.is_none_or(|rate| self.tokens >= rate as f64)
+    }
+
+    fn available(&self) -> u64 {
+        match self.rate {
+            Some(_) => self.tokens.max(0.0) as u64,
+            None => u64::MAX,
+        }
+    }
+
+    /// How many bytes to hold out for before letting a stream through.
+    fn target(&self, wanted: u64) -> u64 {
+        let target = wanted.min(MIN_CHUNK);
+        match self.rate {
+            Some(rate) => target.min(rate.max(1)),
+            None => target,
+        }
+    }
+
+    fn wait_for(&self, bytes: u64) -> Duration {
+        match self.rate {
+            Some(rate) if rate > 0 => {
+                Duration::from_secs_f64((bytes as f64 - self.tokens).max(0.0) / rate as f64)
+            }
+            // A zero rate pauses the direction; check back once a second in
+            // case the limit is lifted.
+            Some(_) => Duration::from_secs(1),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Takes `bytes` tokens. Reads can't be undone, so this may leave the
+    /// bucket in debt, which later callers wait out.
+    fn consume(&mut self, bytes: u64) {
+        if self.rate.is_some() {
+            self.tokens -= bytes as f64;
+        }
+    }
+}
+
+struct Buckets {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Buckets {
+    fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        Self {
+            upload: TokenBucket::new(upload),
+            download: TokenBucket::new(download),
+        }
+    }
+
+    fn get(&mut self, direction: Direction) -> &mut TokenBucket {
+        match direction {
+            Direction::Upload => &mut self.upload,
+            Direction::Download => &mut self.download,
+        }
+    }
+
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.upload.refill(now);
+        self.download.refill(now);
+        self.upload.is_full() && self.download.is_full()
+    }
+}
+
+struct ThrottleState {
+    limits: BandwidthLimits,
+    global: Buckets,
+    peers: HashMap<PeerId, Buckets>,
+}
+
+/// Global and per-peer token buckets for bitswap streams. Limits can be
+/// changed at any time and apply to streams that are already open.
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    state: Arc<Mutex<ThrottleState>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(limits: BandwidthLimits) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ThrottleState {
+                limits,
+                global: Buckets::new(limits.upload_bytes_per_sec, limits.download_bytes_per_sec),
+                peers: HashMap::new(),
+            })),
+        }
+    }
+
+    pub(crate) fn limits(&self) -> BandwidthLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    pub(crate) fn set_limits(&self, limits: BandwidthLimits) {
+        let mut state = self.state.lock().unwrap();
+        state.limits = limits;
+        state.global.upload.set_rate(limits.upload_bytes_per_sec);
+        state
+            .global
+            .download
+            .set_rate(limits.download_bytes_per_sec);
+        for buckets in state.peers.values_mut() {
+            buckets.upload.set_rate(limits.peer_upload_bytes_per_sec);
+            buckets
+                .download
+                .set_rate(limits.peer_download_bytes_per_sec);
+        }
+    }
+
+    /// Returns how many of `wanted` bytes may move now, or how long to wait
+    /// before asking again.
+    pub(crate) fn admit(
+        &self,
+        peer: PeerId,
+        direction: Direction,
+        wanted: usize,
+    ) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let ThrottleState {
+            limits,
+            global,
+            peers,
+        } = &mut *state;
+        if peers.len() > MAX_IDLE_PEERS {
+            peers.retain(|_, buckets| !buckets.is_idle(now));
+        }
+        let peer = peers.entry(peer).or_insert_with(|| {
+            Buckets::new(
+                limits.peer_upload_bytes_per_sec,
+                limits.peer_download_bytes_per_sec,
+            )
+        });
+
+        let buckets = [global.get(direction), peer.get(direction)];
+        let wanted = wanted as u64;
+        let mut allowed = wanted;
+        let mut wait = Duration::ZERO;
+        for bucket in buckets {
+            bucket.refill(now);
+            let target = bucket.target(wanted);
+            if bucket.available() < target {
+                wait = wait.max(bucket.wait_for(target));
+            }
+            allowed = allowed.min(bucket.available());
+        }
+
+        if wait.is_zero() && allowed > 0 {
+            Ok(allowed as usize)
+        } else {
+            Err(wait.max(Duration::from_millis(1)))
+        }
+    }
+
+    pub(crate) fn consume(&self, peer: PeerId, direction: Direction, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.global.get(direction).consume(bytes as u64);
+        if let Some(buckets) = state.peers.get_mut(&peer) {
+            buckets.get(direction).consume(bytes as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_bucket_always_has_room() {
+        let bucket = TokenBucket::new(None);
+        assert_eq!(bucket.available(), u64::MAX);
+        assert!(bucket.wait_for(u64::MAX).is_zero());
+    }
+
+    #[test]
+    fn large_read_leaves_bucket_in_debt() {
+        let mut bucket = TokenBucket::new(Some(1000));
+        bucket.consume(4000);
+        let wait = bucket.wait_for(bucket.target(500));
+        // 3000 bytes of debt plus the 500 asked for, at 1000 bytes a second.
+        assert!(wait > Duration::from_millis(3400) && wait <= Duration::from_millis(3500));
+    }
+
+    #[test]
+    fn zero_rate_pauses() {
+        let peer = PeerId::random();
+        let throttle = Throttle::new(BandwidthLimits {
+            download_bytes_per_sec: Some(0),
+            ..Default::default()
+        });
+        assert!(throttle.admit(peer, Direction::Download, 1).is_err());
+        assert_eq!(throttle.admit(peer, Direction::Upload, 1), Ok(1));
+        throttle.set_limits(BandwidthLimits::default());
+        assert_eq!(throttle.admit(peer, Direction::Download, 1), Ok(1));
+    }
+
+    #[test]
+    fn peer_limit_leaves_other_peers_alone() {
+        let (busy, other) = (PeerId::random(), PeerId::random());
+        let throttle = Throttle::new(BandwidthLimits {
+            peer_upload_bytes_per_sec: Some(1000),
+            ..Default::default()
+        });
+        assert_eq!(throttle.admit(busy, Direction::Upload, 8192), Ok(1000));
+        throttle.consume(busy, Direction::Upload, 1000);
+        assert!(throttle.admit(busy, Direction::Upload, 8192).is_err());
+        assert_eq!(throttle.admit(other, Direction::Upload, 8192), Ok(1000));
+    }
+}
@@ -0,0 +1,64 @@
+use crate::bandwidth::BandwidthMeter;
+use futures::future::Either;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, OptionalTransport};
+use libp2p::core::upgrade::Version;
+use libp2p::pnet::{PnetConfig, PnetError, PnetOutput, PreSharedKey};
+use libp2p::{dns, identity, noise, quic, tcp, websocket, yamux, PeerId, Transport};
+use std::error::Error;
+
+/// QUIC, TCP and WebSocket, with every connection going through the
+/// bandwidth meter. `SwarmBuilder`'s own versions of these have no room for
+/// the meter, so they go in through `with_other_transport`.
+///
+/// With a pre-shared key TCP and WebSocket connections start with a pnet
+/// handshake. QUIC can't carry one and is left out on private networks.
+pub(crate) fn direct_transport(
+    keypair: &identity::Keypair,
+    psk: Option<PreSharedKey>,
+    meter: BandwidthMeter,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let tcp = || tcp::tokio::Transport::new(tcp::Config::default().nodelay(true));
+    let stream_based = tcp()
+        .or_transport(websocket::WsConfig::new(dns::tokio::Transport::system(
+            tcp(),
+        )?))
+        .and_then(move |socket, _| protect(socket, psk))
+        .upgrade(Version::V1Lazy)
+        .authenticate(noise::Config::new(keypair)?)
+        .multiplex(yamux::Config::default())
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+
+    let quic = match psk {
+        Some(_) => OptionalTransport::none(),
+        None => OptionalTransport::some(
+            quic::tokio::Transport::new(quic::Config::new(keypair))
+                .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer))),
+        ),
+    };
+
+    let transport = quic
+        .or_transport(stream_based)
+        .map(|output, _| output.into_inner())
+        .map(move |(peer, muxer), _| (peer, meter.meter_connection(peer, muxer)))
+        .boxed();
+    Ok(transport)
+}
+
+/// Runs the pnet handshake on a raw connection when we're on a private network.
+async fn protect<S>(
+    socket: S,
+    psk: Option<PreSharedKey>,
+) -> Result<Either<PnetOutput<S>, S>, PnetError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    match psk {
+        Some(psk) => PnetConfig::new(psk)
+            .handshake(socket)
+            .await
+            .map(Either::Left),
+        None => Ok(Either::Right(socket)),
+    }
+}
@@ -45,6 +45,7 @@ export type EmailScopedPayloadSchema = z.infer<typeof emailScopedPayloadSchema>;
 export type EncryptedScopedIdToken = z.infer<typeof scopedPayloadSchema>;
 export type PeerInfo = {
     peer_id: string;
-    listening_addr: string;
-    node_type: string;
+    addresses: string[];
+    node_type: string | null;
+    score: number;
   };
@@ -10,6 +10,7 @@ import GoogleLogo from "../components/GoogleLogo";
 import { collapseAddress } from "../core/utils";
 import { ProvidedFile, Peer } from './types.ts';
 import { useFileManager } from '../utils/fileUtils.tsx';
+import { errorMessage, isBoxPeerError } from '../utils/errorUtils.ts';
 import { getReward, getTotalEarned, upload_content } from '../core/contracts.ts';
 import { Aptos, AptosConfig, Network, U64 } from "@aptos-labs/ts-sdk";
 
@@ -70,8 +71,8 @@ const ProviderDashboard: React.FC = () => {
         try {
             const id = await invoke<string>('start_listening');
             setPeerId(id);
-        } catch (error: any) {
-            message.error(error);
+        } catch (error) {
+            message.error(errorMessage(error));
         }
     };
 
@@ -142,7 +143,13 @@ const ProviderDashboard: React.FC = () => {
             }
         } catch (error) {
             console.error("Error locking file:", error);
-            message.error("Failed to lock file");
+            if (isBoxPeerError(error) && error.kind === 'NotFound') {
+                message.error("Nobody on the network is providing this file right now");
+            } else if (isBoxPeerError(error) && error.kind === 'StorageFull') {
+                message.error("Not enough storage left to provide this file");
+            } else {
+                message.error(`Failed to lock file: ${errorMessage(error)}`);
+            }
         } finally {
             setLoading(false);
         }
@@ -240,7 +247,7 @@ const ProviderDashboard: React.FC = () => {
             setProvidedFiles([...providedFiles, selectedFile]);
         } catch (error: any) {
             console.error(error);
-            message.error(`Failed to provide file: ${error}`);
+            message.error(`Failed to provide file: ${errorMessage(error)}`);
         }
         finally {
             setLoading(false)
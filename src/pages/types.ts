@@ -1,7 +1,10 @@
 export interface PeerDashboardProps { }
 
 export interface Peer {
-    id: string;
+    peer_id: string;
+    addresses: string[];
+    node_type: 'Provider' | 'Distributor' | 'Consumer' | null;
+    score: number;
 }
 
 export interface ProvidedFile {
@@ -30,4 +33,33 @@ export interface PreviewContextType {
     previewContent: PreviewContent[];
     updatePreviewContent: (cid: string, element: JSX.Element, fileObject: FileObject) => void;
     getPreviewByCid: (cid: string) => PreviewContent | undefined;
-}
\ No newline at end of file
+}
+
+export interface PeerConnection {
+    address: string;
+    transport: string;
+    direction: 'Outbound' | 'Inbound';
+    uptime_secs: number;
+}
+
+export interface PeerDetails {
+    peer_id: string;
+    listen_addrs: string[];
+    observed_addr: string | null;
+    protocols: string[];
+    agent_version: string | null;
+    protocol_version: string | null;
+    rtt_ms: number | null;
+    connections: PeerConnection[];
+    node_type: 'Provider' | 'Distributor' | 'Consumer' | null;
+    score: number;
+}
+
+export interface BoxPeerError {
+    kind: 'NotFound' | 'Timeout' | 'InvalidCid' | 'InvalidInput' | 'StorageFull' | 'NotConnected'
+        | 'NetworkUnavailable' | 'Cancelled' | 'Network' | 'Storage' | 'Internal';
+    message: string;
+    used?: number;
+    quota?: number;
+    needed?: number;
+}
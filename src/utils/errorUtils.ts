@@ -0,0 +1,9 @@
+import { BoxPeerError } from '../pages/types.ts';
+
+export const isBoxPeerError = (error: unknown): error is BoxPeerError => {
+    return typeof error === 'object' && error !== null && 'kind' in error && 'message' in error;
+};
+
+export const errorMessage = (error: unknown): string => {
+    return isBoxPeerError(error) ? error.message : String(error);
+};